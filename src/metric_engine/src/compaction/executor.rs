@@ -33,12 +33,12 @@ use tokio::sync::mpsc::Sender;
 use tracing::{debug, error, trace};
 
 use crate::{
-    compaction::Task,
+    compaction::{CompactionPhase, Task, TaskTracker},
     ensure,
     manifest::{ManifestRef, ManifestUpdate},
     read::ParquetReader,
     sst::{FileId, FileMeta, SstFile, SstPathGenerator},
-    types::{ObjectStoreRef, RuntimeRef, StorageSchema},
+    types::{ObjectStoreRef, RuntimeRef, StorageSchema, TimeRange},
     Result,
 };
 
@@ -57,7 +57,9 @@ struct Inner {
     write_props: WriterProperties,
     inused_memory: AtomicU64,
     mem_limit: u64,
+    new_sst_max_size: u64,
     trigger_tx: Sender<()>,
+    tracker: TaskTracker,
 }
 
 impl Executor {
@@ -71,7 +73,9 @@ impl Executor {
         parquet_reader: Arc<ParquetReader>,
         write_props: WriterProperties,
         mem_limit: u64,
+        new_sst_max_size: u64,
         trigger_tx: Sender<()>,
+        tracker: TaskTracker,
     ) -> Self {
         let inner = Inner {
             runtime,
@@ -82,8 +86,10 @@ impl Executor {
             parquet_reader,
             write_props,
             mem_limit,
+            new_sst_max_size,
             inused_memory: AtomicU64::new(0),
             trigger_tx,
+            tracker,
         };
         Self {
             inner: Arc::new(inner),
@@ -114,6 +120,7 @@ impl Executor {
     }
 
     pub fn on_success(&self, task: &Task) {
+        self.inner.tracker.remove(task);
         let task_size = task.input_size();
         self.inner
             .inused_memory
@@ -121,6 +128,7 @@ impl Executor {
     }
 
     pub fn on_failure(&self, task: &Task) {
+        self.inner.tracker.remove(task);
         let task_size = task.input_size();
         self.inner
             .inused_memory
@@ -150,17 +158,63 @@ impl Executor {
         }
     }
 
-    // TODO: Merge input sst files into one new sst file
-    // and delete the expired sst files
+    // Merge input sst files into new sst files and delete the expired sst
+    // files
     pub async fn do_compaction(&self, task: &Task) -> Result<()> {
         self.pre_check(task)?;
         self.trigger_more_task();
+        self.inner.tracker.set_phase(task, CompactionPhase::Merging);
 
         debug!(input_len = task.inputs.len(), "Start do compaction");
         let mut time_range = task.inputs[0].meta().time_range.clone();
         for f in &task.inputs[1..] {
             time_range.merge(&f.meta().time_range);
         }
+        let mut to_adds = Vec::new();
+        if let Err(e) = self.write_ssts(task, &time_range, &mut to_adds).await {
+            self.delete_ssts(to_adds.iter().map(|f| f.id()));
+            return Err(e);
+        }
+        let added_ids = to_adds.iter().map(|f| f.id()).collect::<Vec<_>>();
+
+        // First add new sst to manifest, then delete expired/old sst
+        let to_deletes = task
+            .expireds
+            .iter()
+            .map(|f| f.id())
+            .chain(task.inputs.iter().map(|f| f.id()))
+            .collect::<Vec<_>>();
+        self.inner
+            .tracker
+            .set_phase(task, CompactionPhase::UpdatingManifest);
+        if let Err(e) = self
+            .inner
+            .manifest
+            .update(ManifestUpdate::new(to_adds, to_deletes.clone()))
+            .await
+        {
+            // New ssts are not visible, remove them.
+            self.delete_ssts(added_ids.into_iter());
+            return Err(e);
+        }
+
+        // From now on, no error should be returned!
+        // Because we have already updated manifest.
+        self.inner
+            .tracker
+            .set_phase(task, CompactionPhase::DeletingFiles);
+        self.delete_ssts(to_deletes.into_iter());
+        Ok(())
+    }
+
+    /// Merge inputs of the task into new ssts, ssts closed are pushed into
+    /// `to_adds` even when error is returned, so they could be cleaned up.
+    async fn write_ssts(
+        &self,
+        task: &Task,
+        time_range: &TimeRange,
+        to_adds: &mut Vec<SstFile>,
+    ) -> Result<()> {
         let plan = self.inner.parquet_reader.build_df_plan(
             task.inputs.clone(),
             None,       // projection
@@ -170,24 +224,69 @@ impl Executor {
         let mut stream = execute_stream(plan, Arc::new(TaskContext::default()))
             .context("execute datafusion plan")?;
 
+        // Output is split into multiple ssts when it exceeds `new_sst_max_size`,
+        // since huge sst hurts read parallelism and is costly to retry upload.
+        let mut current_writer = None;
+        while let Some(batch) = stream.next().await {
+            let batch = batch.context("execute plan")?;
+            if batch.num_rows() == 0 {
+                continue;
+            }
+            if current_writer.is_none() {
+                current_writer = Some(self.create_sst_writer()?);
+            }
+            let sst_writer = current_writer.as_mut().unwrap();
+            sst_writer.num_rows += batch.num_rows();
+            sst_writer
+                .writer
+                .write(&batch)
+                .await
+                .context("write batch")?;
+            let written_size =
+                sst_writer.writer.bytes_written() + sst_writer.writer.in_progress_size();
+            if written_size as u64 >= self.inner.new_sst_max_size {
+                let sst_writer = current_writer.take().unwrap();
+                to_adds.push(self.close_sst_writer(sst_writer, time_range).await?);
+            }
+        }
+        if let Some(sst_writer) = current_writer.take() {
+            to_adds.push(self.close_sst_writer(sst_writer, time_range).await?);
+        }
+
+        Ok(())
+    }
+
+    fn create_sst_writer(&self) -> Result<SstWriter> {
         let file_id = SstFile::allocate_id();
-        let file_path = self.inner.sst_path_gen.generate(file_id);
-        let file_path = Path::from(file_path);
+        let file_path = Path::from(self.inner.sst_path_gen.generate(file_id));
         let object_store_writer =
             ParquetObjectWriter::new(self.inner.store.clone(), file_path.clone());
-        let mut writer = AsyncArrowWriter::try_new(
+        let writer = AsyncArrowWriter::try_new(
             object_store_writer,
             self.inner.schema.arrow_schema.clone(),
             Some(self.inner.write_props.clone()),
         )
         .context("create arrow writer")?;
-        let mut num_rows = 0;
-        // TODO: support multi-part write
-        while let Some(batch) = stream.next().await {
-            let batch = batch.context("execute plan")?;
-            num_rows += batch.num_rows();
-            writer.write(&batch).await.context("write batch")?;
-        }
+
+        Ok(SstWriter {
+            file_id,
+            file_path,
+            writer,
+            num_rows: 0,
+        })
+    }
+
+    async fn close_sst_writer(
+        &self,
+        sst_writer: SstWriter,
+        time_range: &TimeRange,
+    ) -> Result<SstFile> {
+        let SstWriter {
+            file_id,
+            file_path,
+            writer,
+            num_rows,
+        } = sst_writer;
         writer.close().await.context("close writer")?;
         let object_meta = self
             .inner
@@ -201,24 +300,9 @@ impl Executor {
             size: object_meta.size as u32,
             time_range: time_range.clone(),
         };
-        debug!(file_meta = ?file_meta, "Compact output new sst");
-        // First add new sst to manifest, then delete expired/old sst
-        let to_adds = vec![SstFile::new(file_id, file_meta)];
-        let to_deletes = task
-            .expireds
-            .iter()
-            .map(|f| f.id())
-            .chain(task.inputs.iter().map(|f| f.id()))
-            .collect::<Vec<_>>();
-        self.inner
-            .manifest
-            .update(ManifestUpdate::new(to_adds, to_deletes.clone()))
-            .await?;
+        debug!(file_id, file_meta = ?file_meta, "Compact output new sst");
 
-        // From now on, no error should be returned!
-        // Because we have already updated manifest.
-        self.delete_ssts(to_deletes.into_iter());
-        Ok(())
+        Ok(SstFile::new(file_id, file_meta))
     }
 
     fn delete_ssts<I>(&self, ids: I)
@@ -253,6 +337,13 @@ impl Executor {
     }
 }
 
+struct SstWriter {
+    file_id: FileId,
+    file_path: Path,
+    writer: AsyncArrowWriter<ParquetObjectWriter>,
+    num_rows: usize,
+}
+
 pub struct Runnable {
     executor: Executor,
     task: Task,
@@ -271,3 +362,173 @@ impl Runnable {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use arrow::array::RecordBatch;
+    use futures::TryStreamExt;
+    use object_store::local::LocalFileSystem;
+    use tokio::{runtime::Runtime, sync::mpsc};
+
+    use super::*;
+    use crate::{
+        arrow_schema,
+        config::{ManifestConfig, UpdateMode},
+        manifest::Manifest,
+        record_batch,
+    };
+
+    async fn build_executor(
+        root_dir: String,
+        runtime: RuntimeRef,
+        new_sst_max_size: u64,
+    ) -> Executor {
+        let store: ObjectStoreRef = Arc::new(LocalFileSystem::new());
+        let schema = StorageSchema::try_new(
+            arrow_schema!(("pk1", UInt8), ("value", Int64)),
+            1,
+            UpdateMode::Overwrite,
+        )
+        .unwrap();
+        let manifest = Manifest::try_new(
+            root_dir.clone(),
+            store.clone(),
+            runtime.clone(),
+            ManifestConfig::default(),
+        )
+        .await
+        .unwrap();
+        let sst_path_gen = Arc::new(SstPathGenerator::new(root_dir));
+        let parquet_reader = Arc::new(ParquetReader::new(
+            store.clone(),
+            schema.clone(),
+            sst_path_gen.clone(),
+            64, // max_merge_fan_in
+        ));
+        let (trigger_tx, _) = mpsc::channel(1);
+        Executor::new(
+            runtime,
+            store,
+            schema,
+            Arc::new(manifest),
+            sst_path_gen,
+            parquet_reader,
+            WriterProperties::default(),
+            u64::MAX, // mem_limit
+            new_sst_max_size,
+            trigger_tx,
+            TaskTracker::default(),
+        )
+    }
+
+    async fn write_sst(executor: &Executor, batch: RecordBatch) -> SstFile {
+        let mut sst_writer = executor.create_sst_writer().unwrap();
+        if batch.num_rows() > 0 {
+            let batch = executor
+                .inner
+                .schema
+                .fill_builtin_columns(batch, sst_writer.file_id)
+                .unwrap();
+            sst_writer.num_rows += batch.num_rows();
+            sst_writer.writer.write(&batch).await.unwrap();
+        }
+        let sst = executor
+            .close_sst_writer(sst_writer, &(0..10).into())
+            .await
+            .unwrap();
+        executor
+            .inner
+            .manifest
+            .add_file(sst.id(), sst.meta().clone())
+            .await
+            .unwrap();
+        sst.mark_compaction();
+        sst
+    }
+
+    async fn list_sst_paths(executor: &Executor, root_dir: &str) -> Vec<Path> {
+        executor
+            .inner
+            .store
+            .list(Some(&Path::from(root_dir)))
+            .map_ok(|meta| meta.location)
+            .try_filter(|path| futures::future::ready(path.as_ref().ends_with(".sst")))
+            .try_collect()
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_compaction_split_outputs() {
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let rt = Arc::new(Runtime::new().unwrap());
+        let root_dir = root_dir.path().to_string_lossy().to_string();
+        rt.clone().block_on(async move {
+            // Every batch written will exceed the max size.
+            let executor = build_executor(
+                root_dir.clone(),
+                rt,
+                1, // new_sst_max_size
+            )
+            .await;
+            let mut inputs = Vec::new();
+            for pks in [vec![1, 2], vec![3], vec![4]] {
+                let batch = record_batch!(
+                    ("pk1", UInt8, pks.clone()),
+                    ("value", Int64, vec![1; pks.len()])
+                )
+                .unwrap();
+                inputs.push(write_sst(&executor, batch).await);
+            }
+            let task = Task {
+                inputs: inputs.clone(),
+                expireds: vec![],
+            };
+            executor.do_compaction(&task).await.unwrap();
+
+            let outputs = executor.inner.manifest.all_ssts().await;
+            assert!(outputs.len() > 1, "{outputs:?}");
+            assert_eq!(outputs.iter().map(|f| f.meta().num_rows).sum::<u32>(), 4);
+            let mut expected_paths = outputs
+                .iter()
+                .map(|f| Path::from(executor.inner.sst_path_gen.generate(f.id())))
+                .collect::<Vec<_>>();
+            expected_paths.sort();
+            let mut paths = list_sst_paths(&executor, &root_dir).await;
+            paths.sort();
+            assert_eq!(paths, expected_paths);
+        });
+    }
+
+    #[test]
+    fn test_compaction_empty_output() {
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let rt = Arc::new(Runtime::new().unwrap());
+        let root_dir = root_dir.path().to_string_lossy().to_string();
+        rt.clone().block_on(async move {
+            let executor = build_executor(
+                root_dir.clone(),
+                rt,
+                1024 * 1024, // new_sst_max_size
+            )
+            .await;
+            let empty = record_batch!(
+                ("pk1", UInt8, Vec::<u8>::new()),
+                ("value", Int64, Vec::<i64>::new())
+            )
+            .unwrap();
+            let inputs = vec![
+                write_sst(&executor, empty.clone()).await,
+                write_sst(&executor, empty).await,
+            ];
+            let task = Task {
+                inputs,
+                expireds: vec![],
+            };
+            executor.do_compaction(&task).await.unwrap();
+
+            assert!(executor.inner.manifest.all_ssts().await.is_empty());
+            assert!(list_sst_paths(&executor, &root_dir).await.is_empty());
+        });
+    }
+}
@@ -19,9 +19,15 @@ mod executor;
 mod picker;
 mod scheduler;
 
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
 pub use scheduler::Scheduler as CompactionScheduler;
+use serde::Serialize;
 
-use crate::sst::SstFile;
+use crate::sst::{FileId, SstFile};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Task {
@@ -33,4 +39,143 @@ impl Task {
     pub fn input_size(&self) -> u64 {
         self.inputs.iter().map(|f| f.size() as u64).sum()
     }
+
+    /// Sst can only be in one task at a time, so the first sst identifies the
+    /// task.
+    fn key(&self) -> Option<FileId> {
+        self.inputs
+            .first()
+            .or_else(|| self.expireds.first())
+            .map(|f| f.id())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum CompactionPhase {
+    /// Picked, but not started by executor yet.
+    Queued,
+    /// Merging input ssts into new ssts.
+    Merging,
+    UpdatingManifest,
+    DeletingFiles,
+}
+
+/// Status of a running or queued compaction task.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CompactionTaskStatus {
+    pub inputs: Vec<FileId>,
+    pub expireds: Vec<FileId>,
+    pub input_size: u64,
+    /// Time when the task is picked, in milliseconds.
+    pub create_time: i64,
+    /// Time when the task is started by executor, in milliseconds.
+    pub start_time: Option<i64>,
+    pub phase: CompactionPhase,
+}
+
+/// Tracks compaction tasks from being picked until finished.
+#[derive(Debug, Clone, Default)]
+pub struct TaskTracker {
+    tasks: Arc<Mutex<HashMap<FileId, CompactionTaskStatus>>>,
+}
+
+impl TaskTracker {
+    pub fn add(&self, task: &Task) {
+        let Some(key) = task.key() else {
+            return;
+        };
+        let status = CompactionTaskStatus {
+            inputs: task.inputs.iter().map(|f| f.id()).collect(),
+            expireds: task.expireds.iter().map(|f| f.id()).collect(),
+            input_size: task.input_size(),
+            create_time: common::now(),
+            start_time: None,
+            phase: CompactionPhase::Queued,
+        };
+        self.tasks.lock().unwrap().insert(key, status);
+    }
+
+    pub fn set_phase(&self, task: &Task, phase: CompactionPhase) {
+        let Some(key) = task.key() else {
+            return;
+        };
+        if let Some(status) = self.tasks.lock().unwrap().get_mut(&key) {
+            if status.start_time.is_none() && phase != CompactionPhase::Queued {
+                status.start_time = Some(common::now());
+            }
+            status.phase = phase;
+        }
+    }
+
+    pub fn remove(&self, task: &Task) {
+        if let Some(key) = task.key() {
+            self.tasks.lock().unwrap().remove(&key);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.lock().unwrap().len()
+    }
+
+    /// Returns tasks ordered by create time.
+    pub fn list(&self) -> Vec<CompactionTaskStatus> {
+        let mut tasks = self
+            .tasks
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        tasks.sort_by_key(|t| (t.create_time, t.inputs.first().copied()));
+        tasks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sst::FileMeta;
+
+    fn build_sst(id: FileId, size: u32) -> SstFile {
+        SstFile::new(
+            id,
+            FileMeta {
+                max_sequence: id,
+                num_rows: 1,
+                size,
+                time_range: (1..10).into(),
+            },
+        )
+    }
+
+    #[test]
+    fn test_task_tracker() {
+        let tracker = TaskTracker::default();
+        let task1 = Task {
+            inputs: vec![build_sst(1, 10), build_sst(2, 20)],
+            expireds: vec![build_sst(3, 5)],
+        };
+        let task2 = Task {
+            inputs: vec![],
+            expireds: vec![build_sst(4, 5)],
+        };
+        tracker.add(&task1);
+        tracker.add(&task2);
+        tracker.set_phase(&task1, CompactionPhase::Merging);
+
+        let tasks = tracker.list();
+        assert_eq!(tasks.len(), 2);
+        let status1 = tasks.iter().find(|t| t.inputs == vec![1, 2]).unwrap();
+        assert_eq!(status1.expireds, vec![3]);
+        assert_eq!(status1.input_size, 30);
+        assert_eq!(status1.phase, CompactionPhase::Merging);
+        assert!(status1.start_time.is_some());
+        let status2 = tasks.iter().find(|t| t.expireds == vec![4]).unwrap();
+        assert_eq!(status2.phase, CompactionPhase::Queued);
+        assert!(status2.start_time.is_none());
+
+        tracker.remove(&task1);
+        tracker.remove(&task2);
+        assert!(tracker.list().is_empty());
+    }
 }
@@ -93,15 +93,22 @@ impl TimeWindowCompactionStrategy {
         let files_by_segment = self.files_by_segment(uncompacted_files);
         let compaction_files = self.pick_compaction_files(files_by_segment)?;
 
-        if compaction_files.is_empty() && expired_files.is_empty() {
-            return None;
+        // Files may be claimed by others(such as delete_range) after the check
+        // above, so only files claimed successfully are used.
+        let mut compaction_files = Self::claim_files(compaction_files);
+        if compaction_files.len() < self.input_sst_min_num {
+            for f in compaction_files.drain(..) {
+                f.unmark_compaction();
+            }
         }
+        let expired_files = Self::claim_files(expired_files);
 
-        for f in &compaction_files {
-            f.mark_compaction();
-        }
-        for f in &expired_files {
-            f.mark_compaction();
+        if compaction_files.is_empty() {
+            // Executor requires at least one input file.
+            for f in &expired_files {
+                f.unmark_compaction();
+            }
+            return None;
         }
 
         let task = Task {
@@ -114,6 +121,13 @@ impl TimeWindowCompactionStrategy {
         Some(task)
     }
 
+    fn claim_files(files: Vec<SstFile>) -> Vec<SstFile> {
+        files
+            .into_iter()
+            .filter(|f| f.try_mark_compaction())
+            .collect()
+    }
+
     fn find_uncompacted_and_expired_files(
         files: Vec<SstFile>,
         expire_time: Option<Timestamp>,
@@ -15,20 +15,29 @@
 // specific language governing permissions and limitations
 // under the License.
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use anyhow::Context;
 use parquet::file::properties::WriterProperties;
 use tokio::{
-    sync::mpsc::{self, Receiver, Sender},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
     task::JoinHandle,
     time::sleep,
 };
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
 
 use super::{executor::Executor, picker::Picker};
 use crate::{
-    compaction::Task,
+    compaction::{CompactionTaskStatus, Task, TaskTracker},
     config::SchedulerConfig,
     manifest::ManifestRef,
     read::ParquetReader,
@@ -37,11 +46,15 @@ use crate::{
     Result,
 };
 
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
 #[allow(dead_code)]
 pub struct Scheduler {
     runtime: RuntimeRef,
 
     trigger_tx: Sender<()>,
+    pause_state: Arc<PauseState>,
+    tracker: TaskTracker,
     task_handle: JoinHandle<()>,
     picker_handle: JoinHandle<()>,
 }
@@ -61,6 +74,11 @@ impl Scheduler {
     ) -> Self {
         let (task_tx, task_rx) = mpsc::channel(config.max_pending_compaction_tasks);
         let (trigger_tx, trigger_rx) = mpsc::channel::<()>(1);
+        let pause_state = Arc::new(PauseState {
+            paused: AtomicBool::new(config.paused),
+            ..Default::default()
+        });
+        let tracker = TaskTracker::default();
         let task_handle = {
             let store = store.clone();
             let manifest = manifest.clone();
@@ -73,7 +91,9 @@ impl Scheduler {
                 parquet_reader,
                 write_props,
                 config.memory_limit.0,
+                config.new_sst_max_size.0,
                 trigger_tx.clone(),
+                tracker.clone(),
             );
 
             runtime.spawn(async move {
@@ -81,6 +101,8 @@ impl Scheduler {
             })
         };
         let picker_handle = {
+            let pause_state = pause_state.clone();
+            let tracker = tracker.clone();
             runtime.spawn(async move {
                 let picker = Picker::new(
                     manifest,
@@ -90,19 +112,92 @@ impl Scheduler {
                     config.input_sst_max_num,
                     config.input_sst_min_num,
                 );
-                Self::generate_task_loop(task_tx, trigger_rx, picker, config.schedule_interval.0)
-                    .await;
+                let schedule_interval = config.schedule_interval.0;
+                Self::generate_task_loop(
+                    task_tx,
+                    trigger_rx,
+                    picker,
+                    schedule_interval,
+                    pause_state,
+                    tracker,
+                    config,
+                )
+                .await;
             })
         };
 
         Self {
             runtime,
             trigger_tx,
+            pause_state,
+            tracker,
             task_handle,
             picker_handle,
         }
     }
 
+    /// Stop picking new compaction tasks, tasks already submitted will still
+    /// run to completion.
+    pub fn pause(&self) {
+        self.pause_state.paused.store(true, Ordering::Relaxed);
+        info!("Compaction paused");
+    }
+
+    pub fn resume(&self) {
+        self.pause_state.paused.store(false, Ordering::Relaxed);
+        info!("Compaction resumed");
+        // Pick a task immediately instead of waiting for next schedule interval.
+        if let Err(e) = self.trigger_tx.try_send(()) {
+            debug!("Send trigger signal after resume failed, err:{e:?}");
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.pause_state.is_paused()
+    }
+
+    /// Stop picking new tasks and wait until submitted tasks finish, picking
+    /// is resumed when all returned guards are dropped.
+    ///
+    /// Unlike `pause`, it's not affected by `resume` and can be nested.
+    pub async fn pause_until_idle(&self) -> PauseGuard {
+        self.pause_state
+            .guard_pauses
+            .fetch_add(1, Ordering::Relaxed);
+        let guard = PauseGuard {
+            pause_state: self.pause_state.clone(),
+            trigger_tx: self.trigger_tx.clone(),
+        };
+        // Wait for the picking in progress, so its task is tracked before
+        // waiting for idle.
+        drop(self.pause_state.pick_lock.lock().await);
+        self.wait_idle().await;
+
+        guard
+    }
+
+    /// Wait until there are no queued or running tasks, it's usually called
+    /// after pause, otherwise new tasks may be picked at any time.
+    pub async fn wait_idle(&self) {
+        while self.tracker.len() > 0 {
+            sleep(IDLE_CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Stop picking new tasks, and wait for tasks already submitted to finish.
+    pub async fn close(&self) {
+        self.pause();
+        self.picker_handle.abort();
+        self.wait_idle().await;
+        self.task_handle.abort();
+        info!("Compaction scheduler closed");
+    }
+
+    /// Returns compaction tasks which are queued or running.
+    pub fn tasks(&self) -> Vec<CompactionTaskStatus> {
+        self.tracker.list()
+    }
+
     pub fn trigger_compaction(&self) -> Result<()> {
         self.trigger_tx
             .try_send(())
@@ -123,38 +218,86 @@ impl Scheduler {
         mut trigger_rx: Receiver<()>,
         mut picker: Picker,
         schedule_interval: Duration,
+        pause_state: Arc<PauseState>,
+        tracker: TaskTracker,
+        config: SchedulerConfig,
     ) {
         info!(
             schedule_interval = ?schedule_interval,
             "Scheduler generate task loop started"
         );
         let send_task = |task| {
+            tracker.add(&task);
             if let Err(e) = task_tx.try_send(task) {
                 warn!("Send task failed, err:{e:?}");
+                tracker.remove(&e.into_inner());
             }
         };
 
-        // Generate one task immediately
-        if let Some(task) = picker.pick_candidate().await {
-            send_task(task);
-        }
+        // Generate one task immediately, then after each interval or trigger.
         loop {
+            let pick_guard = pause_state.pick_lock.lock().await;
+            let running_tasks = tracker.len();
+            let max_running_tasks = config.max_running_tasks(common::now());
+            if pause_state.is_paused() {
+                debug!("Compaction is paused, skip pick candidate");
+            } else if max_running_tasks.is_some_and(|max| running_tasks >= max) {
+                debug!(
+                    running_tasks,
+                    max_running_tasks, "Outside off-peak windows, skip pick candidate"
+                );
+            } else if let Some(task) = picker.pick_candidate().await {
+                send_task(task);
+            }
+            drop(pick_guard);
+
             tokio::select! {
-                _ = sleep(schedule_interval) => {
-                    if let Some(task) = picker.pick_candidate().await {
-                        send_task(task);
-                    }
-                }
+                _ = sleep(schedule_interval) => {}
                 signal = trigger_rx.recv() => {
                     if signal.is_none() {
                         info!("Scheduler generate task loop stopped");
                         return;
                     }
-                    if let Some(task) = picker.pick_candidate().await {
-                        send_task(task);
-                    }
                 }
             }
         }
     }
 }
+
+#[derive(Default)]
+struct PauseState {
+    // Paused by user, see `Scheduler::pause` and `Scheduler::resume`.
+    paused: AtomicBool,
+    // Number of alive `PauseGuard`s, picking is skipped when it's not zero.
+    guard_pauses: AtomicUsize,
+    // Held while picking and submitting a task.
+    pick_lock: Mutex<()>,
+}
+
+impl PauseState {
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed) || self.guard_pauses.load(Ordering::Relaxed) > 0
+    }
+}
+
+/// Returned by `Scheduler::pause_until_idle`, picking is resumed after all
+/// guards are dropped.
+pub struct PauseGuard {
+    pause_state: Arc<PauseState>,
+    trigger_tx: Sender<()>,
+}
+
+impl Drop for PauseGuard {
+    fn drop(&mut self) {
+        if self
+            .pause_state
+            .guard_pauses
+            .fetch_sub(1, Ordering::Relaxed)
+            == 1
+        {
+            if let Err(e) = self.trigger_tx.try_send(()) {
+                debug!("Send trigger signal after pause guard dropped failed, err:{e:?}");
+            }
+        }
+    }
+}
@@ -15,12 +15,15 @@
 // specific language governing permissions and limitations
 // under the License.
 
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt, str::FromStr};
 
+use anyhow::Context;
 use common::{ReadableDuration, ReadableSize};
-use parquet::basic::{Compression, Encoding, ZstdLevel};
+use parquet::basic::{Compression, Encoding, GzipLevel, ZstdLevel};
 use serde::{Deserialize, Serialize};
 
+use crate::{AnyhowError, Result};
+
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(default, deny_unknown_fields)]
 pub struct SchedulerConfig {
@@ -33,6 +36,13 @@ pub struct SchedulerConfig {
     pub new_sst_max_size: ReadableSize,
     pub input_sst_max_num: usize,
     pub input_sst_min_num: usize,
+    // Compaction runs without limit only in these windows, when they are set.
+    pub off_peak_windows: Vec<TimeWindow>,
+    // Max number of picked but unfinished tasks outside off-peak windows.
+    pub peak_max_running_tasks: usize,
+    // Open with compaction paused, pause by API is only kept in memory, so it
+    // should be set here to keep compaction paused after restart.
+    pub paused: bool,
 }
 
 impl Default for SchedulerConfig {
@@ -45,7 +55,108 @@ impl Default for SchedulerConfig {
             new_sst_max_size: ReadableSize::gb(1_u64),
             input_sst_max_num: 30,
             input_sst_min_num: 5,
+            off_peak_windows: Vec::new(),
+            peak_max_running_tasks: 1,
+            paused: false,
+        }
+    }
+}
+
+impl SchedulerConfig {
+    /// Returns the max number of running tasks at `now`(in milliseconds),
+    /// `None` means no limit.
+    pub fn max_running_tasks(&self, now: i64) -> Option<usize> {
+        if self.off_peak_windows.is_empty() {
+            return None;
+        }
+
+        let minute_of_day = (now / 60_000).rem_euclid(MINUTES_PER_DAY as i64) as u32;
+        if self
+            .off_peak_windows
+            .iter()
+            .any(|w| w.contains(minute_of_day))
+        {
+            None
+        } else {
+            Some(self.peak_max_running_tasks)
+        }
+    }
+}
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// Daily time window in UTC, formatted as `HH:MM-HH:MM`.
+///
+/// End may be earlier than start, which means the window crosses midnight,
+/// e.g. `22:00-06:00`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeWindow {
+    // Minutes since midnight.
+    start: u32,
+    end: u32,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, minute_of_day: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= minute_of_day && minute_of_day < self.end
+        } else {
+            minute_of_day >= self.start || minute_of_day < self.end
+        }
+    }
+
+    fn parse_minutes(s: &str) -> Option<u32> {
+        let (hour, minute) = s.trim().split_once(':')?;
+        let hour = hour.parse::<u32>().ok()?;
+        let minute = minute.parse::<u32>().ok()?;
+        // 24:00 is allowed as end of day.
+        if minute >= 60 || hour * 60 + minute > MINUTES_PER_DAY {
+            return None;
+        }
+        Some(hour * 60 + minute)
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("invalid time window {s:?}, expected format is HH:MM-HH:MM");
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let start = Self::parse_minutes(start).ok_or_else(invalid)?;
+        let end = Self::parse_minutes(end).ok_or_else(invalid)?;
+        if start == end {
+            return Err(format!("time window {s:?} is empty"));
         }
+        Ok(Self { start, end })
+    }
+}
+
+impl TryFrom<String> for TimeWindow {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+impl From<TimeWindow> for String {
+    fn from(value: TimeWindow) -> Self {
+        value.to_string()
     }
 }
 
@@ -80,6 +191,8 @@ pub enum ParquetCompression {
     #[default]
     Uncompressed,
     Snappy,
+    Lz4,
+    Gzip,
     Zstd,
 }
 
@@ -88,11 +201,43 @@ impl From<ParquetCompression> for Compression {
         match value {
             ParquetCompression::Uncompressed => Compression::UNCOMPRESSED,
             ParquetCompression::Snappy => Compression::SNAPPY,
+            ParquetCompression::Lz4 => Compression::LZ4_RAW,
+            ParquetCompression::Gzip => Compression::GZIP(GzipLevel::default()),
             ParquetCompression::Zstd => Compression::ZSTD(ZstdLevel::default()),
         }
     }
 }
 
+impl ParquetCompression {
+    /// Convert to parquet compression with the given level, `None` means the
+    /// default level of the codec is used.
+    pub fn with_level(self, level: Option<i32>) -> Result<Compression> {
+        let compression = match (self, level) {
+            (codec, None) => codec.into(),
+            (ParquetCompression::Gzip, Some(level)) => {
+                let level = u32::try_from(level)
+                    .ok()
+                    .and_then(|v| GzipLevel::try_new(v).ok())
+                    .with_context(|| format!("invalid gzip compression level:{level}"))?;
+                Compression::GZIP(level)
+            }
+            (ParquetCompression::Zstd, Some(level)) => {
+                let level = ZstdLevel::try_new(level)
+                    .with_context(|| format!("invalid zstd compression level:{level}"))?;
+                Compression::ZSTD(level)
+            }
+            (codec, Some(level)) => {
+                return Err(AnyhowError::msg(format!(
+                    "compression level is not supported by {codec:?}, level:{level}"
+                ))
+                .into());
+            }
+        };
+
+        Ok(compression)
+    }
+}
+
 #[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(default, deny_unknown_fields)]
 pub struct ColumnOptions {
@@ -100,8 +245,13 @@ pub struct ColumnOptions {
     pub enable_bloom_filter: Option<bool>,
     pub encoding: Option<ParquetEncoding>,
     pub compression: Option<ParquetCompression>,
+    pub compression_level: Option<i32>,
 }
 
+// Sort needs some headroom for merging spilled runs, budget below this is
+// too small to make progress.
+const MIN_SORT_MEMORY_LIMIT_MB: u64 = 4;
+
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 #[serde(default, deny_unknown_fields)]
 pub struct WriteConfig {
@@ -111,10 +261,23 @@ pub struct WriteConfig {
     // use to set column props with default value
     pub enable_dict: bool,
     pub enable_bloom_filter: bool,
+    // Enable bloom filter for primary key columns, which are the most common
+    // columns used in equality predicates.
+    pub enable_primary_key_bloom_filter: bool,
     pub encoding: ParquetEncoding,
     pub compression: ParquetCompression,
+    pub compression_level: Option<i32>,
     // use to set column props with column name
     pub column_options: Option<HashMap<String, ColumnOptions>>,
+    // Memory budget for sorting one batch before writing it to SST, batch larger
+    // than it will be sorted in runs, which are spilled to disk and then merged.
+    // `None` means always sort in memory.
+    // Note it only bounds the sorting, the whole batch to write is still held in
+    // memory before it's split into runs, except for ingested files, which are
+    // read and spilled run by run.
+    pub sort_memory_limit: Option<ReadableSize>,
+    // Directory for sort spill files, OS temp dir will be used if not set.
+    pub sort_spill_dir: Option<String>,
 }
 
 impl Default for WriteConfig {
@@ -125,9 +288,13 @@ impl Default for WriteConfig {
             enable_sorting_columns: true,
             enable_dict: false,
             enable_bloom_filter: false,
+            enable_primary_key_bloom_filter: false,
             encoding: ParquetEncoding::Plain,
             compression: ParquetCompression::Snappy,
+            compression_level: None,
             column_options: None,
+            sort_memory_limit: None,
+            sort_spill_dir: None,
         }
     }
 }
@@ -140,6 +307,13 @@ pub struct ManifestConfig {
     pub min_merge_threshold: usize,
     pub hard_merge_threshold: usize,
     pub soft_merge_threshold: usize,
+    // When open, truncate deltas at the first corrupted one instead of failing,
+    // discarded deltas are moved to `manifest/corrupted`. Files added by them
+    // are lost, so only enable this to recover from corruption. Deltas failing
+    // checksum or decoding are corrupted, while read errors still fail the open.
+    // Ssts not found in object store after salvaging, e.g. inputs of a discarded
+    // compaction, are dropped from manifest and logged.
+    pub salvage_corrupted_deltas: bool,
 }
 
 impl Default for ManifestConfig {
@@ -150,6 +324,23 @@ impl Default for ManifestConfig {
             min_merge_threshold: 10,
             soft_merge_threshold: 50,
             hard_merge_threshold: 90,
+            salvage_corrupted_deltas: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScanConfig {
+    // Max number of sorted inputs merged by one merge operator, inputs exceeding
+    // this will be pre-merged in groups first.
+    pub max_merge_fan_in: usize,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        Self {
+            max_merge_fan_in: 64,
         }
     }
 }
@@ -158,9 +349,231 @@ impl Default for ManifestConfig {
 #[serde(default, deny_unknown_fields)]
 pub struct StorageConfig {
     pub write: WriteConfig,
+    pub scan: ScanConfig,
     pub manifest: ManifestConfig,
     pub scheduler: SchedulerConfig,
     pub update_mode: UpdateMode,
+    // Number of leading primary keys used to dedup rows, `None` means all primary
+    // keys.
+    pub num_dedup_keys: Option<usize>,
+    pub quota: QuotaConfig,
+}
+
+impl StorageConfig {
+    /// Check the whole config, all violations are reported at once.
+    pub fn validate(&self) -> Result<()> {
+        let errors = self.violations();
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(AnyhowError::msg(format!(
+            "Invalid storage config:\n  - {}",
+            errors.join("\n  - ")
+        ))
+        .into())
+    }
+
+    /// Returns all violations of the config, each one contains the option
+    /// path and a suggested fix.
+    pub fn violations(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        self.write.validate(&mut errors);
+        self.scan.validate(&mut errors);
+        self.manifest.validate(&mut errors);
+        self.scheduler.validate(&mut errors);
+        self.quota.validate(&mut errors);
+        if self.num_dedup_keys == Some(0) {
+            errors.push(
+                "num_dedup_keys must be positive, remove it to dedup by all primary keys"
+                    .to_string(),
+            );
+        }
+        errors
+    }
+}
+
+impl WriteConfig {
+    fn validate(&self, errors: &mut Vec<String>) {
+        if self.max_row_group_size == 0 {
+            errors.push("write.max_row_group_size must be positive, try 8192".to_string());
+        }
+        if self.write_bacth_size == 0 {
+            errors.push("write.write_bacth_size must be positive, try 1024".to_string());
+        }
+        if let Err(e) = self.compression.clone().with_level(self.compression_level) {
+            errors.push(format!(
+                "write.compression_level is invalid, remove it to use default level, err:{e}"
+            ));
+        }
+        for (col_name, col_opt) in self.column_options.iter().flatten() {
+            if col_opt.compression.is_none() && col_opt.compression_level.is_none() {
+                continue;
+            }
+            let compression = col_opt
+                .compression
+                .clone()
+                .unwrap_or_else(|| self.compression.clone());
+            if let Err(e) = compression.with_level(col_opt.compression_level) {
+                errors.push(format!(
+                    "write.column_options.{col_name}.compression_level is invalid, remove it to use default level, err:{e}"
+                ));
+            }
+        }
+        if let Some(limit) = self.sort_memory_limit {
+            if limit < ReadableSize::mb(MIN_SORT_MEMORY_LIMIT_MB) {
+                errors.push(format!(
+                    "write.sort_memory_limit must be at least {MIN_SORT_MEMORY_LIMIT_MB}MB, current:{}B, try 256MB",
+                    limit.as_byte()
+                ));
+            }
+        }
+    }
+}
+
+impl ScanConfig {
+    fn validate(&self, errors: &mut Vec<String>) {
+        if self.max_merge_fan_in < 2 {
+            errors.push(format!(
+                "scan.max_merge_fan_in must be at least 2, current:{}, try 64",
+                self.max_merge_fan_in
+            ));
+        }
+    }
+}
+
+impl ManifestConfig {
+    fn validate(&self, errors: &mut Vec<String>) {
+        if self.channel_size == 0 {
+            errors.push("manifest.channel_size must be positive, try 3".to_string());
+        }
+        if self.merge_interval_seconds == 0 {
+            errors.push("manifest.merge_interval_seconds must be positive, try 5".to_string());
+        }
+        if !(self.min_merge_threshold <= self.soft_merge_threshold
+            && self.soft_merge_threshold <= self.hard_merge_threshold)
+        {
+            errors.push(format!(
+                "manifest merge thresholds must satisfy min <= soft <= hard, current:{}/{}/{}",
+                self.min_merge_threshold, self.soft_merge_threshold, self.hard_merge_threshold
+            ));
+        }
+    }
+}
+
+impl SchedulerConfig {
+    fn validate(&self, errors: &mut Vec<String>) {
+        if self.schedule_interval.is_zero() {
+            errors.push("scheduler.schedule_interval must be positive, try \"10s\"".to_string());
+        }
+        if self.max_pending_compaction_tasks == 0 {
+            errors.push(
+                "scheduler.max_pending_compaction_tasks must be positive, try 10".to_string(),
+            );
+        }
+        if self.input_sst_min_num < 2 {
+            errors.push(format!(
+                "scheduler.input_sst_min_num must be at least 2, current:{}",
+                self.input_sst_min_num
+            ));
+        }
+        if self.input_sst_min_num > self.input_sst_max_num {
+            errors.push(format!(
+                "scheduler.input_sst_min_num({}) must not exceed input_sst_max_num({})",
+                self.input_sst_min_num, self.input_sst_max_num
+            ));
+        }
+        if self.new_sst_max_size.0 > self.memory_limit.0 {
+            errors.push(format!(
+                "scheduler.new_sst_max_size({}MB) must not exceed memory_limit({}MB), otherwise no compaction task can run",
+                self.new_sst_max_size.as_mb(), self.memory_limit.as_mb()
+            ));
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokenBucketConfig {
+    // Max number of requests per second, `None` means unlimited.
+    pub ops_per_sec: Option<u64>,
+    // Max number of bytes transferred per second, `None` means unlimited.
+    pub bytes_per_sec: Option<ReadableSize>,
+}
+
+impl TokenBucketConfig {
+    pub fn is_limited(&self) -> bool {
+        self.ops_per_sec.is_some() || self.bytes_per_sec.is_some()
+    }
+
+    fn validate(&self, name: &str, errors: &mut Vec<String>) {
+        if self.ops_per_sec == Some(0) {
+            errors.push(format!(
+                "{name}.ops_per_sec must be positive, remove it to disable the limit"
+            ));
+        }
+        if self.bytes_per_sec.is_some_and(|v| v.as_byte() == 0) {
+            errors.push(format!(
+                "{name}.bytes_per_sec must be positive, remove it to disable the limit"
+            ));
+        }
+    }
+}
+
+/// Quotas of one storage, requests exceeding them are rejected with
+/// `Error::QuotaExceeded` instead of waiting.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    // Max number of rows written per second, `None` means unlimited.
+    pub write_rows_per_sec: Option<u64>,
+    // Max number of sst bytes scanned per second, `None` means unlimited.
+    // Bytes read from ssts after projection and pruning are counted while
+    // scanning, the footer is excluded. Scans are rejected once previous ones
+    // used it up.
+    pub scan_bytes_per_sec: Option<ReadableSize>,
+}
+
+impl QuotaConfig {
+    fn validate(&self, errors: &mut Vec<String>) {
+        if self.write_rows_per_sec == Some(0) {
+            errors.push(
+                "quota.write_rows_per_sec must be positive, remove it to disable the quota"
+                    .to_string(),
+            );
+        }
+        if self.scan_bytes_per_sec.is_some_and(|v| v.as_byte() == 0) {
+            errors.push(
+                "quota.scan_bytes_per_sec must be positive, remove it to disable the quota"
+                    .to_string(),
+            );
+        }
+    }
+}
+
+/// Rate limits of object store requests, reads and writes are limited
+/// separately.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    // Limits of get/head/list.
+    pub read: TokenBucketConfig,
+    // Limits of put/delete/copy/rename.
+    pub write: TokenBucketConfig,
+}
+
+impl RateLimitConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.read.is_limited() || self.write.is_limited()
+    }
+
+    /// Returns all violations of the config, see
+    /// [`StorageConfig::violations`].
+    pub fn violations(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        self.read.validate("read", &mut errors);
+        self.write.validate("write", &mut errors);
+        errors
+    }
 }
 
 #[derive(Debug, Default, Clone, Deserialize, Serialize, PartialEq)]
@@ -170,3 +583,112 @@ pub enum UpdateMode {
     Overwrite,
     Append,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_storage_config() {
+        assert!(StorageConfig::default().validate().is_ok());
+
+        let config = StorageConfig {
+            write: WriteConfig {
+                sort_memory_limit: Some(ReadableSize::kb(512)),
+                ..Default::default()
+            },
+            scan: ScanConfig {
+                max_merge_fan_in: 1,
+            },
+            manifest: ManifestConfig {
+                channel_size: 0,
+                ..Default::default()
+            },
+            scheduler: SchedulerConfig {
+                input_sst_min_num: 10,
+                input_sst_max_num: 5,
+                ..Default::default()
+            },
+            quota: QuotaConfig {
+                write_rows_per_sec: Some(0),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let msg = config.validate().unwrap_err().to_string();
+        for expected in [
+            "write.sort_memory_limit",
+            "scan.max_merge_fan_in",
+            "manifest.channel_size",
+            "scheduler.input_sst_min_num(10)",
+            "quota.write_rows_per_sec",
+        ] {
+            assert!(msg.contains(expected), "{expected} not found in {msg}");
+        }
+    }
+
+    #[test]
+    fn test_time_window() {
+        let window: TimeWindow = "01:30-06:00".parse().unwrap();
+        assert_eq!(window.to_string(), "01:30-06:00");
+        assert!(!window.contains(89));
+        assert!(window.contains(90));
+        assert!(window.contains(359));
+        assert!(!window.contains(360));
+
+        // Cross midnight
+        let window: TimeWindow = "22:00-02:00".parse().unwrap();
+        assert!(window.contains(23 * 60));
+        assert!(window.contains(0));
+        assert!(window.contains(119));
+        assert!(!window.contains(120));
+        assert!(!window.contains(21 * 60));
+
+        for invalid in [
+            "",
+            "01:00",
+            "1-2",
+            "25:00-01:00",
+            "01:60-02:00",
+            "03:00-03:00",
+        ] {
+            assert!(invalid.parse::<TimeWindow>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_max_running_tasks() {
+        let mut config = SchedulerConfig::default();
+        assert_eq!(config.max_running_tasks(0), None);
+
+        config.off_peak_windows = vec!["00:00-06:00".parse().unwrap()];
+        config.peak_max_running_tasks = 2;
+        let hour = 3_600_000;
+        assert_eq!(config.max_running_tasks(hour), None);
+        assert_eq!(config.max_running_tasks(12 * hour), Some(2));
+        // Next day
+        assert_eq!(config.max_running_tasks(25 * hour), None);
+    }
+
+    #[test]
+    fn test_rate_limit_config() {
+        assert!(!RateLimitConfig::default().is_enabled());
+        assert!(RateLimitConfig::default().violations().is_empty());
+
+        let config = RateLimitConfig {
+            read: TokenBucketConfig {
+                ops_per_sec: Some(0),
+                bytes_per_sec: Some(ReadableSize::mb(100)),
+            },
+            write: TokenBucketConfig {
+                ops_per_sec: Some(100),
+                bytes_per_sec: Some(ReadableSize(0)),
+            },
+        };
+        assert!(config.is_enabled());
+        let errors = config.violations();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("read.ops_per_sec"));
+        assert!(errors[1].starts_with("write.bytes_per_sec"));
+    }
+}
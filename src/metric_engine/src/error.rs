@@ -23,6 +23,10 @@ use thiserror::Error;
 pub enum Error {
     #[error(transparent)]
     Internal(#[from] anyhow::Error),
+    /// Request is rejected since the storage's quota is used up, it could be
+    /// retried later.
+    #[error("quota exceeded, {0}")]
+    QuotaExceeded(String),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -27,6 +27,7 @@ pub mod operator;
 mod read;
 pub mod sst;
 pub mod storage;
+pub mod store;
 #[cfg(test)]
 mod test_util;
 pub mod types;
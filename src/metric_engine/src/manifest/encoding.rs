@@ -20,6 +20,7 @@ use std::io::{Cursor, Read, Write};
 use anyhow::Context;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use bytes::{Buf, Bytes};
+use prost::Message;
 
 use crate::{
     ensure,
@@ -43,6 +44,72 @@ impl ManifestUpdate {
     }
 }
 
+/// The layout for manifest delta:
+/// ```plaintext
+/// +-------------+--------------+-----------------+------------------+
+/// | magic(u32)  | version(u8)  | checksum(u32)   | payload(bytes)   |
+/// +-------------+--------------+-----------------+------------------+
+/// ```
+/// - The payload is the protobuf encoded `ManifestUpdate`, and the checksum is
+///   the crc32 of it.
+/// - Deltas written before the header was introduced only contain the payload,
+///   they are still readable but not verified.
+impl ManifestUpdate {
+    pub const DELTA_HEADER_LENGTH: usize = 4 /*magic*/ + 1 /*version*/ + 4 /*checksum*/;
+    pub const DELTA_MAGIC: u32 = 0xCAFE_5678;
+    pub const DELTA_VERSION: u8 = 1;
+
+    pub fn encode_delta(self) -> Result<Bytes> {
+        let pb_update = pb_types::ManifestUpdate::from(self);
+        let mut buf = Vec::with_capacity(Self::DELTA_HEADER_LENGTH + pb_update.encoded_len());
+        buf.write_u32::<LittleEndian>(Self::DELTA_MAGIC)
+            .context("write shall not fail.")?;
+        buf.write_u8(Self::DELTA_VERSION)
+            .context("write shall not fail.")?;
+        // Placeholder for checksum, filled after payload is encoded.
+        buf.write_u32::<LittleEndian>(0)
+            .context("write shall not fail.")?;
+        pb_update
+            .encode(&mut buf)
+            .context("failed to encode manifest update")?;
+        let checksum = crc32fast::hash(&buf[Self::DELTA_HEADER_LENGTH..]);
+        buf[Self::DELTA_HEADER_LENGTH - 4..Self::DELTA_HEADER_LENGTH]
+            .copy_from_slice(&checksum.to_le_bytes());
+
+        Ok(Bytes::from(buf))
+    }
+
+    pub fn decode_delta(bytes: Bytes) -> Result<Self> {
+        let has_header = bytes.len() >= Self::DELTA_HEADER_LENGTH
+            && bytes[..4] == Self::DELTA_MAGIC.to_le_bytes();
+        let payload = if has_header {
+            let mut cursor = Cursor::new(&bytes[4..Self::DELTA_HEADER_LENGTH]);
+            let version = cursor.read_u8().context("read delta version")?;
+            ensure!(
+                version <= Self::DELTA_VERSION,
+                "unsupported delta version, version:{version}, max supported:{}",
+                Self::DELTA_VERSION
+            );
+            let expected = cursor
+                .read_u32::<LittleEndian>()
+                .context("read delta checksum")?;
+            let payload = bytes.slice(Self::DELTA_HEADER_LENGTH..);
+            let actual = crc32fast::hash(&payload);
+            ensure!(
+                expected == actual,
+                "delta checksum mismatch, expected:{expected}, actual:{actual}"
+            );
+            payload
+        } else {
+            bytes
+        };
+        let pb_update =
+            pb_types::ManifestUpdate::decode(payload).context("failed to decode delta")?;
+
+        Self::try_from(pb_update)
+    }
+}
+
 impl TryFrom<pb_types::ManifestUpdate> for ManifestUpdate {
     type Error = Error;
 
@@ -120,6 +187,13 @@ impl SnapshotHeader {
             "invalid bytes to convert to header."
         );
         let version = reader.read_u8().context("read snapshot header version")?;
+        // Refuse to read snapshot written by newer version, since records may be
+        // misread, and rewriting it would lose data.
+        ensure!(
+            version <= SnapshotRecord::VERSION,
+            "unsupported snapshot version, version:{version}, max supported:{}",
+            SnapshotRecord::VERSION
+        );
         let flag = reader.read_u8().context("read snapshot header flag")?;
         let length = reader
             .read_u64::<LittleEndian>()
@@ -277,8 +351,7 @@ impl TryFrom<Bytes> for Snapshot {
         let header = SnapshotHeader::try_new(&mut cursor)?;
         let record_total_length = header.length as usize;
         ensure!(
-            record_total_length > 0
-                && record_total_length % SnapshotRecord::LENGTH == 0
+            record_total_length % SnapshotRecord::LENGTH == 0
                 && record_total_length + SnapshotHeader::LENGTH == bytes_len,
             "create snapshot from bytes failed, header:{header:?}, bytes_length: {bytes_len}",
         );
@@ -361,6 +434,15 @@ mod tests {
             },
             header
         );
+
+        let header = SnapshotHeader {
+            version: SnapshotRecord::VERSION + 1,
+            ..SnapshotHeader::new()
+        };
+        let mut vec = vec![0u8; SnapshotHeader::LENGTH];
+        header.write_to(vec.as_mut_slice()).unwrap();
+        let err = SnapshotHeader::try_new(Cursor::new(vec)).unwrap_err();
+        assert!(err.to_string().contains("unsupported snapshot version"));
     }
 
     #[test]
@@ -392,4 +474,55 @@ mod tests {
             record
         );
     }
+
+    #[test]
+    fn test_delta_encoding() {
+        let update = ManifestUpdate::new(
+            vec![SstFile::new(
+                99,
+                FileMeta {
+                    max_sequence: 99,
+                    num_rows: 100,
+                    size: 938,
+                    time_range: (100..200).into(),
+                },
+            )],
+            vec![1, 2],
+        );
+        let check = |decoded: ManifestUpdate| {
+            assert_eq!(decoded.to_adds, update.to_adds);
+            assert_eq!(decoded.to_deletes, update.to_deletes);
+        };
+        let bytes = update.clone().encode_delta().unwrap();
+        check(ManifestUpdate::decode_delta(bytes.clone()).unwrap());
+
+        // Flip one bit in payload.
+        let mut corrupted = bytes.to_vec();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let err = ManifestUpdate::decode_delta(corrupted.into()).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{err}");
+
+        // Deltas without header are still readable.
+        let legacy = pb_types::ManifestUpdate::from(update.clone()).encode_to_vec();
+        check(ManifestUpdate::decode_delta(legacy.into()).unwrap());
+    }
+
+    #[test]
+    fn test_empty_snapshot() {
+        let mut snapshot = Snapshot::default();
+        let meta = FileMeta {
+            max_sequence: 1,
+            num_rows: 1,
+            size: 1,
+            time_range: (0..1).into(),
+        };
+        snapshot.add_records(vec![SstFile::new(1, meta)]);
+        snapshot.delete_records(vec![1]);
+
+        // Snapshot with only header is written when all ssts are deleted.
+        let bytes = snapshot.into_bytes().unwrap();
+        assert_eq!(bytes.len(), SnapshotHeader::LENGTH);
+        let snapshot = Snapshot::try_from(bytes).unwrap();
+        assert!(snapshot.into_ssts().is_empty());
+    }
 }
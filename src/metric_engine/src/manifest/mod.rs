@@ -25,22 +25,21 @@ use std::{
 };
 
 use anyhow::Context;
-use async_scoped::TokioScope;
 use bytes::Bytes;
 pub use encoding::{ManifestUpdate, Snapshot};
-use futures::{StreamExt, TryStreamExt};
+use futures::{future, FutureExt, StreamExt, TryStreamExt};
 use itertools::Itertools;
 use object_store::{path::Path, PutPayload};
-use prost::Message;
+use serde::Serialize;
 use tokio::sync::{
     mpsc::{self, Receiver, Sender},
-    RwLock,
+    Mutex, RwLock,
 };
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, info, trace, warn};
 
 use crate::{
     config::ManifestConfig,
-    sst::{FileId, FileMeta, SstFile},
+    sst::{FileId, FileMeta, SstFile, SstPathGenerator},
     types::{ObjectStoreRef, RuntimeRef, TimeRange},
     AnyhowError, Result,
 };
@@ -48,6 +47,7 @@ use crate::{
 pub const PREFIX_PATH: &str = "manifest";
 pub const SNAPSHOT_FILENAME: &str = "snapshot";
 pub const DELTA_PREFIX: &str = "delta";
+pub const CORRUPTED_PREFIX: &str = "corrupted";
 
 // Used for manifest delta filename
 // This number mustn't go backwards on restarts, otherwise file id
@@ -64,10 +64,21 @@ static NEXT_ID: LazyLock<AtomicU64> = LazyLock::new(|| {
 
 pub type ManifestRef = Arc<Manifest>;
 
+/// Observer of manifest changes, it's notified after an update is persisted.
+///
+/// It's called in the write path, so implementation should be cheap, heavy
+/// work should be done in background.
+pub trait ManifestObserver: Send + Sync {
+    fn on_update(&self, update: &ManifestUpdate);
+}
+
+pub type ManifestObserverRef = Arc<dyn ManifestObserver>;
+
 pub struct Manifest {
     delta_dir: Path,
     store: ObjectStoreRef,
     merger: Arc<ManifestMerger>,
+    observers: std::sync::RwLock<Vec<ManifestObserverRef>>,
 
     ssts: RwLock<Vec<SstFile>>,
 }
@@ -81,10 +92,13 @@ impl Manifest {
     ) -> Result<Self> {
         let snapshot_path = Path::from(format!("{root_dir}/{PREFIX_PATH}/{SNAPSHOT_FILENAME}"));
         let delta_dir = Path::from(format!("{root_dir}/{PREFIX_PATH}/{DELTA_PREFIX}"));
+        let corrupted_dir = Path::from(format!("{root_dir}/{PREFIX_PATH}/{CORRUPTED_PREFIX}"));
 
         let merger = ManifestMerger::try_new(
             snapshot_path.clone(),
             delta_dir.clone(),
+            corrupted_dir,
+            SstPathGenerator::new(root_dir),
             store.clone(),
             merge_options,
         )
@@ -108,10 +122,15 @@ impl Manifest {
             delta_dir,
             store,
             merger,
+            observers: std::sync::RwLock::new(Vec::new()),
             ssts: RwLock::new(ssts),
         })
     }
 
+    pub fn register_observer(&self, observer: ManifestObserverRef) {
+        self.observers.write().unwrap().push(observer);
+    }
+
     pub async fn add_file(&self, id: FileId, meta: FileMeta) -> Result<()> {
         let update = ManifestUpdate::new(vec![SstFile::new(id, meta)], Vec::new());
         self.update(update).await
@@ -130,38 +149,87 @@ impl Manifest {
 
     pub async fn update_inner(&self, update: ManifestUpdate) -> Result<()> {
         let path = Path::from(format!("{}/{}", self.delta_dir, Self::allocate_id()));
-        let pb_update = pb_types::ManifestUpdate::from(update.clone());
-        let mut buf: Vec<u8> = Vec::with_capacity(pb_update.encoded_len());
-        pb_update
-            .encode(&mut buf)
-            .context("failed to encode manifest update")?;
+        let buf = update.clone().encode_delta()?;
 
         // 1. Persist the delta manifest
         self.store
-            .put(&path, PutPayload::from_bytes(Bytes::from(buf)))
+            .put(&path, PutPayload::from_bytes(buf))
             .await
             .with_context(|| format!("Failed to write delta manifest, path:{}", path))?;
 
         // 2. Update cached payload
         {
             let mut ssts = self.ssts.write().await;
-            for file in update.to_adds {
-                ssts.push(file);
+            for file in &update.to_adds {
+                ssts.push(file.clone());
             }
             // TODO: sort files in payload, so we can delete files more
             // efficiently.
             ssts.retain(|file| !update.to_deletes.contains(&file.id()));
         }
 
+        // 3. Notify observers
+        for observer in self.observers.read().unwrap().iter() {
+            observer.on_update(&update);
+        }
+
         Ok(())
     }
 
+    /// Merge all delta files into snapshot immediately, instead of waiting for
+    /// the background merger.
+    pub async fn merge_deltas(&self) -> Result<()> {
+        self.merger.do_merge(false /* first_run */).await
+    }
+
+    /// Dump snapshot and pending deltas for inspection.
+    pub async fn dump(&self) -> Result<ManifestDump> {
+        // Avoid seeing deltas already merged into snapshot.
+        let _guard = self.merger.merge_lock.lock().await;
+        dump_inner(&self.store, &self.merger.snapshot_path, &self.delta_dir).await
+    }
+
     // TODO: avoid clone
     pub async fn all_ssts(&self) -> Vec<SstFile> {
         let ssts = self.ssts.read().await;
         ssts.clone()
     }
 
+    /// Returns current ssts ordered by time range.
+    pub async fn list_ssts(&self) -> Vec<SstStatus> {
+        let ssts = self.ssts.read().await;
+        let mut ssts = ssts
+            .iter()
+            .map(|f| SstStatus {
+                sst: f.into(),
+                being_compacted: f.is_compaction(),
+            })
+            .collect::<Vec<_>>();
+        ssts.sort_by_key(|s| (s.sst.time_range, s.sst.id));
+        ssts
+    }
+
+    pub async fn summary(&self) -> ManifestSummary {
+        let ssts = self.ssts.read().await;
+        let mut summary = ManifestSummary::default();
+        for f in ssts.iter() {
+            let meta = f.meta();
+            summary.num_ssts += 1;
+            if f.is_compaction() {
+                summary.num_compacting += 1;
+            }
+            summary.total_rows += meta.num_rows as u64;
+            summary.total_size += meta.size as u64;
+            summary.max_sequence = summary.max_sequence.max(meta.max_sequence);
+            let (start, end) = (*meta.time_range.start, *meta.time_range.end);
+            summary.time_range = Some(match summary.time_range {
+                Some((s, e)) => (s.min(start), e.max(end)),
+                None => (start, end),
+            });
+        }
+        summary
+    }
+
     pub async fn find_ssts(&self, time_range: &TimeRange) -> Vec<SstFile> {
         let ssts = self.ssts.read().await;
 
@@ -184,17 +252,24 @@ enum MergeType {
 struct ManifestMerger {
     snapshot_path: Path,
     delta_dir: Path,
+    corrupted_dir: Path,
+    sst_path_gen: SstPathGenerator,
     store: ObjectStoreRef,
     sender: Sender<MergeType>,
     receiver: RwLock<Receiver<MergeType>>,
     deltas_num: AtomicUsize,
     merge_options: ManifestConfig,
+    // Merge could be triggered manually, this ensures only one merge runs at a
+    // time, otherwise deltas may be applied twice.
+    merge_lock: Mutex<()>,
 }
 
 impl ManifestMerger {
     async fn try_new(
         snapshot_path: Path,
         delta_dir: Path,
+        corrupted_dir: Path,
+        sst_path_gen: SstPathGenerator,
         store: ObjectStoreRef,
         merge_options: ManifestConfig,
     ) -> Result<Arc<Self>> {
@@ -202,12 +277,15 @@ impl ManifestMerger {
         let merger = Self {
             snapshot_path,
             delta_dir,
+            corrupted_dir,
+            sst_path_gen,
             store,
             sender: tx,
             receiver: RwLock::new(rx),
             // Init this to 0, because we will merge all delta files when startup.
             deltas_num: AtomicUsize::new(0),
             merge_options,
+            merge_lock: Mutex::new(()),
         };
         // Merge all delta files when startup
         merger.do_merge(true /* first_run */).await?;
@@ -272,6 +350,7 @@ impl ManifestMerger {
     }
 
     async fn do_merge(&self, first_run: bool) -> Result<()> {
+        let _guard = self.merge_lock.lock().await;
         let paths = list_delta_paths(&self.store, &self.delta_dir).await?;
         if paths.is_empty() {
             return Ok(());
@@ -280,23 +359,57 @@ impl ManifestMerger {
             self.deltas_num.store(paths.len(), Ordering::Relaxed);
         }
 
-        let (_, results) = TokioScope::scope_and_block(|scope| {
-            for path in &paths {
-                scope.spawn(async { read_delta_file(&self.store, path).await });
+        // Not use `TokioScope::scope_and_block` here, since merge could be triggered
+        // from current_thread runtime, where `block_in_place` panics.
+        let results =
+            future::join_all(paths.iter().map(|path| read_delta_bytes(&self.store, path))).await;
+        // Failing to read deltas may be transient, only deltas which can't be
+        // decoded are considered as corrupted.
+        let mut deltas = paths
+            .into_iter()
+            .zip(results)
+            .map(|(path, res)| {
+                let res = decode_delta(&path, res?);
+                Ok((path, res))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // Deltas are applied in order, so truncate at the first corrupted one to keep
+        // manifest consistent, deltas after it are discarded.
+        let mut corrupted_deltas = Vec::new();
+        if first_run && self.merge_options.salvage_corrupted_deltas {
+            deltas.sort_by_key(|(path, _)| delta_id(path));
+            if let Some(idx) = deltas.iter().position(|(_, res)| res.is_err()) {
+                corrupted_deltas = deltas.split_off(idx);
+                for (path, res) in &corrupted_deltas {
+                    if let Err(e) = res {
+                        error!("Found corrupted manifest delta, path:{path}, err:{e}");
+                    }
+                }
+                warn!(
+                    first_corrupted = %corrupted_deltas[0].0,
+                    discarded_num = corrupted_deltas.len(),
+                    "Truncate manifest deltas at the first corrupted one"
+                );
             }
-        });
+        }
 
         let mut snapshot = read_snapshot(&self.store, &self.snapshot_path).await?;
         trace!(sst_ids = ?snapshot.records.iter().map(|r| r.id()).collect_vec(), "Before snapshot merge deltas");
         // Since the deltas is unsorted, so we have to first add all new files, then
         // delete old files.
         let mut to_deletes = Vec::new();
-        for res in results {
-            let manifest_update = res.context("Failed to join read delta files task")??;
+        let mut paths = Vec::with_capacity(deltas.len());
+        for (path, res) in deltas {
+            let manifest_update = res?;
             snapshot.add_records(manifest_update.to_adds);
             to_deletes.extend(manifest_update.to_deletes);
+            paths.push(path);
         }
         snapshot.delete_records(to_deletes);
+        if !corrupted_deltas.is_empty() {
+            self.drop_missing_ssts(&mut snapshot).await?;
+        }
         trace!(sst_ids = ?snapshot.records.iter().map(|r| r.id()).collect_vec(), "After snapshot merge deltas");
         let snapshot_bytes = snapshot.into_bytes()?;
         let put_payload = PutPayload::from_bytes(snapshot_bytes);
@@ -307,32 +420,200 @@ impl ManifestMerger {
             .with_context(|| format!("Failed to update manifest, path:{}", self.snapshot_path))?;
 
         // 2. Delete the merged manifest files
-        let (_, results) = TokioScope::scope_and_block(|scope| {
-            for path in &paths {
-                trace!(path = ?path, "delete delta file");
-                scope.spawn(async { delete_delta_file(&self.store, path).await });
-            }
+        let deletes = paths.iter().map(|path| {
+            trace!(path = ?path, "delete delta file");
+            delete_delta_file(&self.store, path).boxed()
         });
+        // Keep discarded deltas for investigation.
+        let moves = corrupted_deltas
+            .iter()
+            .map(|(path, _)| self.move_to_corrupted_dir(path).boxed());
+        let results = future::join_all(deletes.chain(moves)).await;
 
         for res in results {
+            if let Err(e) = res {
+                error!("Failed to delete delta, err:{e}")
+            } else {
+                self.dec_delta_num();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Discarded deltas may be compaction deltas whose input ssts are deleted
+    /// already, keeping them in manifest would make scans fail, so drop them.
+    async fn drop_missing_ssts(&self, snapshot: &mut Snapshot) -> Result<()> {
+        let ids = snapshot.records.iter().map(|r| r.id()).collect_vec();
+        let results = future::join_all(ids.iter().map(|id| {
+            let path = Path::from(self.sst_path_gen.generate(*id));
+            async move { self.store.head(&path).await }
+        }))
+        .await;
+        let mut missing = Vec::new();
+        for (id, res) in ids.into_iter().zip(results) {
             match res {
+                Ok(_) => {}
+                Err(object_store::Error::NotFound { .. }) => missing.push(id),
                 Err(e) => {
-                    error!("Failed to join delete delta files task, err:{e}")
-                }
-                Ok(v) => {
-                    if let Err(e) = v {
-                        error!("Failed to delete delta, err:{e}")
-                    } else {
-                        self.dec_delta_num();
-                    }
+                    let context = format!("Failed to check sst exists, id:{id}");
+                    return Err(AnyhowError::new(e).context(context).into());
                 }
             }
         }
+        if !missing.is_empty() {
+            error!(
+                ?missing,
+                "Drop ssts not found in object store after salvaging manifest deltas"
+            );
+            snapshot.delete_records(missing);
+        }
+
+        Ok(())
+    }
+
+    async fn move_to_corrupted_dir(&self, path: &Path) -> Result<()> {
+        let filename = path.filename().unwrap_or_default();
+        let to = Path::from(format!("{}/{filename}", self.corrupted_dir));
+        self.store
+            .rename(path, &to)
+            .await
+            .with_context(|| format!("Failed to move corrupted delta, from:{path}, to:{to}"))?;
 
         Ok(())
     }
 }
 
+/// Delta filename is its id, which is increasing.
+fn delta_id(path: &Path) -> u64 {
+    path.filename()
+        .and_then(|name| name.parse().ok())
+        .unwrap_or(u64::MAX)
+}
+
+/// Manifest state in object store, current ssts are the snapshot with deltas
+/// applied in order.
+#[derive(Debug, Serialize)]
+pub struct ManifestDump {
+    pub snapshot: Vec<SstDump>,
+    /// Deltas not merged into snapshot yet, ordered by id.
+    pub deltas: Vec<DeltaDump>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct SstDump {
+    pub id: FileId,
+    pub max_sequence: u64,
+    pub num_rows: u32,
+    pub size: u32,
+    /// [start, end) in milliseconds.
+    pub time_range: (i64, i64),
+}
+
+impl From<&SstFile> for SstDump {
+    fn from(sst: &SstFile) -> Self {
+        let meta = sst.meta();
+        Self {
+            id: sst.id(),
+            max_sequence: meta.max_sequence,
+            num_rows: meta.num_rows,
+            size: meta.size,
+            time_range: (*meta.time_range.start, *meta.time_range.end),
+        }
+    }
+}
+
+/// A current sst, see [`Manifest::list_ssts`].
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct SstStatus {
+    #[serde(flatten)]
+    pub sst: SstDump,
+    pub being_compacted: bool,
+}
+
+/// Summary of current ssts, see [`Manifest::summary`].
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ManifestSummary {
+    pub num_ssts: usize,
+    pub num_compacting: usize,
+    pub total_rows: u64,
+    pub total_size: u64,
+    pub max_sequence: u64,
+    /// Union of all ssts' time range, `None` when there is no sst.
+    pub time_range: Option<(i64, i64)>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeltaDump {
+    pub path: String,
+    pub to_adds: Vec<SstDump>,
+    pub to_deletes: Vec<FileId>,
+    /// Set when the delta can't be decoded.
+    pub error: Option<String>,
+}
+
+/// Dump manifest under `root_dir` directly from object store, it could be used
+/// without opening the storage.
+pub async fn dump_manifest(store: &ObjectStoreRef, root_dir: &str) -> Result<ManifestDump> {
+    let snapshot_path = Path::from(format!("{root_dir}/{PREFIX_PATH}/{SNAPSHOT_FILENAME}"));
+    let delta_dir = Path::from(format!("{root_dir}/{PREFIX_PATH}/{DELTA_PREFIX}"));
+    dump_inner(store, &snapshot_path, &delta_dir).await
+}
+
+/// Write a manifest snapshot containing `ssts` under `root_dir`, so that the
+/// dir could be opened as a storage once the ssts are copied there too.
+pub async fn write_snapshot(
+    store: &ObjectStoreRef,
+    root_dir: &str,
+    ssts: Vec<SstFile>,
+) -> Result<()> {
+    let snapshot_path = Path::from(format!("{root_dir}/{PREFIX_PATH}/{SNAPSHOT_FILENAME}"));
+    let mut snapshot = Snapshot::default();
+    snapshot.add_records(ssts);
+    let bytes = snapshot.into_bytes()?;
+    store
+        .put(&snapshot_path, PutPayload::from_bytes(bytes))
+        .await
+        .with_context(|| format!("Failed to write manifest snapshot, path:{snapshot_path}"))?;
+
+    Ok(())
+}
+
+async fn dump_inner(
+    store: &ObjectStoreRef,
+    snapshot_path: &Path,
+    delta_dir: &Path,
+) -> Result<ManifestDump> {
+    let snapshot = read_snapshot(store, snapshot_path)
+        .await?
+        .into_ssts()
+        .iter()
+        .map(SstDump::from)
+        .collect();
+    let mut paths = list_delta_paths(store, delta_dir).await?;
+    paths.sort_by_key(delta_id);
+    let mut deltas = Vec::with_capacity(paths.len());
+    for path in paths {
+        let delta = match read_delta_file(store, &path).await {
+            Ok(update) => DeltaDump {
+                path: path.to_string(),
+                to_adds: update.to_adds.iter().map(SstDump::from).collect(),
+                to_deletes: update.to_deletes,
+                error: None,
+            },
+            Err(e) => DeltaDump {
+                path: path.to_string(),
+                to_adds: Vec::new(),
+                to_deletes: Vec::new(),
+                error: Some(e.to_string()),
+            },
+        };
+        deltas.push(delta);
+    }
+
+    Ok(ManifestDump { snapshot, deltas })
+}
+
 async fn read_snapshot(store: &ObjectStoreRef, path: &Path) -> Result<Snapshot> {
     match store.get(path).await {
         Ok(v) => {
@@ -354,6 +635,11 @@ async fn read_snapshot(store: &ObjectStoreRef, path: &Path) -> Result<Snapshot>
 }
 
 async fn read_delta_file(store: &ObjectStoreRef, sst_path: &Path) -> Result<ManifestUpdate> {
+    let bytes = read_delta_bytes(store, sst_path).await?;
+    decode_delta(sst_path, bytes)
+}
+
+async fn read_delta_bytes(store: &ObjectStoreRef, sst_path: &Path) -> Result<Bytes> {
     let bytes = store
         .get(sst_path)
         .await
@@ -362,11 +648,12 @@ async fn read_delta_file(store: &ObjectStoreRef, sst_path: &Path) -> Result<Mani
         .await
         .with_context(|| format!("failed to read delta file, path:{sst_path}"))?;
 
-    let pb_update = pb_types::ManifestUpdate::decode(bytes)
-        .with_context(|| format!("failed to decode delta file, path:{sst_path}"))?;
+    Ok(bytes)
+}
 
-    let update = ManifestUpdate::try_from(pb_update)
-        .with_context(|| format!("failed to convert delta file, path:{sst_path}"))?;
+fn decode_delta(sst_path: &Path, bytes: Bytes) -> Result<ManifestUpdate> {
+    let update = ManifestUpdate::decode_delta(bytes)
+        .with_context(|| format!("failed to decode delta file, path:{sst_path}"))?;
     Ok(update)
 }
 
@@ -506,4 +793,380 @@ mod tests {
             assert!(delta_paths.is_empty());
         })
     }
+
+    #[test]
+    fn test_merge_deltas_manually() {
+        let root_dir = temp_dir::TempDir::new()
+            .unwrap()
+            .path()
+            .to_string_lossy()
+            .to_string();
+        let snapshot_path = Path::from(format!("{root_dir}/{PREFIX_PATH}/{SNAPSHOT_FILENAME}"));
+        let delta_dir = Path::from(format!("{root_dir}/{PREFIX_PATH}/{DELTA_PREFIX}"));
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let rt = runtime.clone();
+
+        rt.block_on(async move {
+            let store: ObjectStoreRef = Arc::new(LocalFileSystem::new());
+            let manifest = Manifest::try_new(
+                root_dir,
+                store.clone(),
+                runtime.clone(),
+                // Avoid background merge
+                ManifestConfig {
+                    merge_interval_seconds: 3600,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+            for i in 0..5 {
+                let time_range = (i..i + 1).into();
+                let meta = FileMeta {
+                    max_sequence: i as u64,
+                    num_rows: i as u32,
+                    size: i as u32,
+                    time_range,
+                };
+                manifest.add_file(i as u64, meta).await.unwrap();
+            }
+            let delta_paths = list_delta_paths(&store, &delta_dir).await.unwrap();
+            assert_eq!(delta_paths.len(), 5);
+
+            manifest.merge_deltas().await.unwrap();
+
+            let delta_paths = list_delta_paths(&store, &delta_dir).await.unwrap();
+            assert!(delta_paths.is_empty());
+            let mut ssts = read_snapshot(&store, &snapshot_path)
+                .await
+                .unwrap()
+                .into_ssts();
+            ssts.sort_by_key(|a| a.id());
+            assert_eq!(
+                ssts.iter().map(|f| f.id()).collect_vec(),
+                vec![0, 1, 2, 3, 4]
+            );
+        })
+    }
+
+    #[test]
+    fn test_salvage_corrupted_deltas() {
+        let root_dir = temp_dir::TempDir::new()
+            .unwrap()
+            .path()
+            .to_string_lossy()
+            .to_string();
+        let delta_dir = Path::from(format!("{root_dir}/{PREFIX_PATH}/{DELTA_PREFIX}"));
+        let corrupted_dir = Path::from(format!("{root_dir}/{PREFIX_PATH}/{CORRUPTED_PREFIX}"));
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let rt = runtime.clone();
+
+        rt.block_on(async move {
+            let store: ObjectStoreRef = Arc::new(LocalFileSystem::new());
+            let config = ManifestConfig {
+                merge_interval_seconds: 3600,
+                ..Default::default()
+            };
+            let manifest = Manifest::try_new(
+                root_dir.clone(),
+                store.clone(),
+                runtime.clone(),
+                config.clone(),
+            )
+            .await
+            .unwrap();
+            for i in 0..3 {
+                let meta = FileMeta {
+                    max_sequence: i,
+                    num_rows: 1,
+                    size: 1,
+                    time_range: (0..1).into(),
+                };
+                put_sst(&store, &root_dir, i).await;
+                manifest.add_file(i, meta).await.unwrap();
+            }
+
+            corrupt_delta(&store, &delta_dir, 1).await;
+
+            // Open fails by default.
+            assert!(Manifest::try_new(
+                root_dir.clone(),
+                store.clone(),
+                runtime.clone(),
+                config.clone()
+            )
+            .await
+            .is_err());
+
+            let manifest = Manifest::try_new(
+                root_dir,
+                store.clone(),
+                runtime.clone(),
+                ManifestConfig {
+                    salvage_corrupted_deltas: true,
+                    ..config
+                },
+            )
+            .await
+            .unwrap();
+            let ssts = manifest.all_ssts().await;
+            assert_eq!(ssts.iter().map(|f| f.id()).collect_vec(), vec![0]);
+            assert!(list_delta_paths(&store, &delta_dir)
+                .await
+                .unwrap()
+                .is_empty());
+            let corrupted_paths = list_delta_paths(&store, &corrupted_dir).await.unwrap();
+            assert_eq!(corrupted_paths.len(), 2);
+        })
+    }
+
+    #[test]
+    fn test_salvage_with_compaction_delta() {
+        let root_dir = temp_dir::TempDir::new()
+            .unwrap()
+            .path()
+            .to_string_lossy()
+            .to_string();
+        let delta_dir = Path::from(format!("{root_dir}/{PREFIX_PATH}/{DELTA_PREFIX}"));
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let rt = runtime.clone();
+
+        rt.block_on(async move {
+            let store: ObjectStoreRef = Arc::new(LocalFileSystem::new());
+            let config = ManifestConfig {
+                merge_interval_seconds: 3600,
+                salvage_corrupted_deltas: true,
+                ..Default::default()
+            };
+            let manifest = Manifest::try_new(
+                root_dir.clone(),
+                store.clone(),
+                runtime.clone(),
+                config.clone(),
+            )
+            .await
+            .unwrap();
+            let meta = FileMeta {
+                max_sequence: 1,
+                num_rows: 1,
+                size: 1,
+                time_range: (0..1).into(),
+            };
+            for i in 0..4 {
+                put_sst(&store, &root_dir, i).await;
+                manifest.add_file(i, meta.clone()).await.unwrap();
+            }
+            // Compact sst 0 and 1 into 4, inputs are deleted after the delta is written.
+            put_sst(&store, &root_dir, 4).await;
+            manifest
+                .update(ManifestUpdate::new(
+                    vec![SstFile::new(4, meta.clone())],
+                    vec![0, 1],
+                ))
+                .await
+                .unwrap();
+            let path_gen = SstPathGenerator::new(root_dir.clone());
+            for id in [0, 1] {
+                store
+                    .delete(&Path::from(path_gen.generate(id)))
+                    .await
+                    .unwrap();
+            }
+
+            // The compaction delta is discarded since it's after the corrupted one, so
+            // its inputs are dropped as they are not found.
+            corrupt_delta(&store, &delta_dir, 3).await;
+            let manifest = Manifest::try_new(root_dir, store.clone(), runtime.clone(), config)
+                .await
+                .unwrap();
+            let ssts = manifest.all_ssts().await;
+            assert_eq!(ssts.iter().map(|f| f.id()).collect_vec(), vec![2]);
+        })
+    }
+
+    async fn put_sst(store: &ObjectStoreRef, root_dir: &str, id: FileId) {
+        let path = SstPathGenerator::new(root_dir.to_string()).generate(id);
+        store
+            .put(&Path::from(path), PutPayload::from_static(b"sst"))
+            .await
+            .unwrap();
+    }
+
+    /// Flip one bit of the `idx`-th delta, it's detected by checksum.
+    async fn corrupt_delta(store: &ObjectStoreRef, delta_dir: &Path, idx: usize) {
+        let mut delta_paths = list_delta_paths(store, delta_dir).await.unwrap();
+        delta_paths.sort_by_key(delta_id);
+        let mut bytes = store
+            .get(&delta_paths[idx])
+            .await
+            .unwrap()
+            .bytes()
+            .await
+            .unwrap()
+            .to_vec();
+        *bytes.last_mut().unwrap() ^= 1;
+        store
+            .put(&delta_paths[idx], PutPayload::from(bytes))
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_dump_manifest() {
+        let root_dir = temp_dir::TempDir::new()
+            .unwrap()
+            .path()
+            .to_string_lossy()
+            .to_string();
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let rt = runtime.clone();
+
+        rt.block_on(async move {
+            let store: ObjectStoreRef = Arc::new(LocalFileSystem::new());
+            let manifest = Manifest::try_new(
+                root_dir.clone(),
+                store.clone(),
+                runtime.clone(),
+                ManifestConfig {
+                    merge_interval_seconds: 3600,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+            let meta = FileMeta {
+                max_sequence: 1,
+                num_rows: 10,
+                size: 100,
+                time_range: (0..10).into(),
+            };
+            manifest.add_file(1, meta.clone()).await.unwrap();
+            manifest.merge_deltas().await.unwrap();
+            manifest
+                .update(ManifestUpdate::new(vec![SstFile::new(2, meta)], vec![1]))
+                .await
+                .unwrap();
+
+            let dump = manifest.dump().await.unwrap();
+            let expected_sst = |id| SstDump {
+                id,
+                max_sequence: 1,
+                num_rows: 10,
+                size: 100,
+                time_range: (0, 10),
+            };
+            assert_eq!(dump.snapshot, vec![expected_sst(1)]);
+            assert_eq!(dump.deltas.len(), 1);
+            assert_eq!(dump.deltas[0].to_adds, vec![expected_sst(2)]);
+            assert_eq!(dump.deltas[0].to_deletes, vec![1]);
+            assert!(dump.deltas[0].error.is_none());
+
+            let offline_dump = dump_manifest(&store, &root_dir).await.unwrap();
+            assert_eq!(offline_dump.snapshot, dump.snapshot);
+            assert_eq!(offline_dump.deltas.len(), 1);
+        })
+    }
+
+    #[test]
+    fn test_list_ssts_and_summary() {
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let rt = runtime.clone();
+
+        rt.block_on(async move {
+            let store: ObjectStoreRef = Arc::new(LocalFileSystem::new());
+            let manifest = Manifest::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                store,
+                runtime.clone(),
+                ManifestConfig::default(),
+            )
+            .await
+            .unwrap();
+            assert_eq!(manifest.summary().await, ManifestSummary::default());
+
+            for (id, time_range) in [(1, 10..20), (2, 0..10), (3, 5..30)] {
+                let meta = FileMeta {
+                    max_sequence: id,
+                    num_rows: 10,
+                    size: 100,
+                    time_range: time_range.into(),
+                };
+                manifest.add_file(id, meta).await.unwrap();
+            }
+            let ssts = manifest.all_ssts().await;
+            ssts.iter().find(|f| f.id() == 3).unwrap().mark_compaction();
+
+            let ssts = manifest.list_ssts().await;
+            assert_eq!(
+                ssts.iter()
+                    .map(|s| (s.sst.id, s.being_compacted))
+                    .collect::<Vec<_>>(),
+                vec![(2, false), (3, true), (1, false)]
+            );
+            assert_eq!(
+                manifest.summary().await,
+                ManifestSummary {
+                    num_ssts: 3,
+                    num_compacting: 1,
+                    total_rows: 30,
+                    total_size: 300,
+                    max_sequence: 3,
+                    time_range: Some((0, 30)),
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn test_manifest_observer() {
+        #[derive(Default)]
+        struct Recorder {
+            updates: std::sync::Mutex<Vec<(Vec<FileId>, Vec<FileId>)>>,
+        }
+
+        impl ManifestObserver for Recorder {
+            fn on_update(&self, update: &ManifestUpdate) {
+                let to_adds = update.to_adds.iter().map(|f| f.id()).collect();
+                self.updates
+                    .lock()
+                    .unwrap()
+                    .push((to_adds, update.to_deletes.clone()));
+            }
+        }
+
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        let rt = runtime.clone();
+        let store = Arc::new(LocalFileSystem::new());
+
+        rt.block_on(async move {
+            let manifest = Manifest::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                store,
+                runtime.clone(),
+                ManifestConfig::default(),
+            )
+            .await
+            .unwrap();
+            let recorder = Arc::new(Recorder::default());
+            manifest.register_observer(recorder.clone());
+
+            let meta = FileMeta {
+                max_sequence: 1,
+                num_rows: 1,
+                size: 1,
+                time_range: (0..1).into(),
+            };
+            manifest.add_file(1, meta.clone()).await.unwrap();
+            manifest
+                .update(ManifestUpdate::new(vec![SstFile::new(2, meta)], vec![1]))
+                .await
+                .unwrap();
+
+            let updates = recorder.updates.lock().unwrap().clone();
+            assert_eq!(updates, vec![(vec![1], vec![]), (vec![2], vec![1])]);
+        });
+    }
 }
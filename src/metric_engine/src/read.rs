@@ -15,7 +15,15 @@
 // specific language governing permissions and limitations
 // under the License.
 
-use std::{any::Any, pin::Pin, sync::Arc, task::Poll};
+use std::{
+    any::Any,
+    future::Future,
+    ops::Range,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Poll},
+    time::Duration,
+};
 
 use anyhow::Context;
 use arrow::{
@@ -26,6 +34,7 @@ use arrow::{
         UInt64Type, UInt8Type,
     },
 };
+use bytes::Bytes;
 use datafusion::{
     common::{internal_err, DFSchema},
     datasource::{
@@ -42,21 +51,26 @@ use datafusion::{
     physical_expr::{create_physical_expr, LexOrdering},
     physical_plan::{
         filter::FilterExec, metrics::ExecutionPlanMetricsSet,
-        sorts::sort_preserving_merge::SortPreservingMergeExec, DisplayAs, Distribution,
-        ExecutionPlan, PlanProperties,
+        sorts::sort_preserving_merge::SortPreservingMergeExec, union::UnionExec, DisplayAs,
+        Distribution, ExecutionPlan, PhysicalExpr, PlanProperties,
     },
     physical_planner::create_physical_sort_exprs,
     prelude::{ident, Expr},
 };
-use futures::{Stream, StreamExt};
+use futures::{future::BoxFuture, Stream, StreamExt};
 use itertools::Itertools;
-use parquet::arrow::async_reader::ParquetObjectReader;
+use parquet::{
+    arrow::async_reader::ParquetObjectReader, errors::Result as ParquetResult,
+    file::metadata::ParquetMetaData,
+};
+use tokio::time::{sleep_until, Instant, Sleep};
 
 use crate::{
     compare_primitive_columns,
     config::UpdateMode,
     operator::{BytesMergeOperator, LastValueOperator, MergeOperator, MergeOperatorRef},
     sst::{SstFile, SstPathGenerator},
+    store::TokenBucket,
     types::{
         ObjectStoreRef, StorageSchema, BUILTIN_COLUMN_NUM, RESERVED_COLUMN_NAME, SEQ_COLUMN_NAME,
     },
@@ -66,12 +80,17 @@ use crate::{
 #[derive(Debug, Clone)]
 pub struct DefaultParquetFileReaderFactory {
     object_store: ObjectStoreRef,
+    // Bytes read are charged to it if set.
+    read_bytes_quota: Option<Arc<TokenBucket>>,
 }
 
 /// Returns a AsyncFileReader factory
 impl DefaultParquetFileReaderFactory {
-    pub fn new(object_store: ObjectStoreRef) -> Self {
-        Self { object_store }
+    pub fn new(object_store: ObjectStoreRef, read_bytes_quota: Option<Arc<TokenBucket>>) -> Self {
+        Self {
+            object_store,
+            read_bytes_quota,
+        }
     }
 }
 
@@ -88,7 +107,44 @@ impl ParquetFileReaderFactory for DefaultParquetFileReaderFactory {
         if let Some(size) = metadata_size_hint {
             reader = reader.with_footer_size_hint(size);
         }
-        Ok(Box::new(reader))
+        match &self.read_bytes_quota {
+            Some(quota) => Ok(Box::new(ChargedFileReader {
+                inner: reader,
+                quota: quota.clone(),
+            })),
+            None => Ok(Box::new(reader)),
+        }
+    }
+}
+
+/// Charges bytes read to the quota, which are column chunks left after
+/// projection and pruning, and page indexes if they are loaded. The footer
+/// is not counted.
+///
+/// Bytes are charged even if they exceed the quota, so the scan goes on and
+/// later scans are rejected until the quota is refilled.
+struct ChargedFileReader {
+    inner: ParquetObjectReader,
+    quota: Arc<TokenBucket>,
+}
+
+impl AsyncFileReader for ChargedFileReader {
+    fn get_bytes(&mut self, range: Range<usize>) -> BoxFuture<'_, ParquetResult<Bytes>> {
+        self.quota.charge(range.len() as u64);
+        self.inner.get_bytes(range)
+    }
+
+    fn get_byte_ranges(
+        &mut self,
+        ranges: Vec<Range<usize>>,
+    ) -> BoxFuture<'_, ParquetResult<Vec<Bytes>>> {
+        self.quota
+            .charge(ranges.iter().map(|r| r.len() as u64).sum());
+        self.inner.get_byte_ranges(ranges)
+    }
+
+    fn get_metadata(&mut self) -> BoxFuture<'_, ParquetResult<Arc<ParquetMetaData>>> {
+        self.inner.get_metadata()
     }
 }
 
@@ -390,10 +446,70 @@ impl RecordBatchStream for MergeStream {
     }
 }
 
+/// Fails the scan once the deadline is reached.
+///
+/// The inner stream is dropped on timeout, which cancels the underlying
+/// execution, so no more objects are read in the background.
+pub struct TimeoutStream {
+    inner: Option<SendableRecordBatchStream>,
+    schema: SchemaRef,
+    timeout: Duration,
+    deadline: Pin<Box<Sleep>>,
+}
+
+impl TimeoutStream {
+    pub fn new(inner: SendableRecordBatchStream, timeout: Duration, deadline: Instant) -> Self {
+        Self {
+            schema: inner.schema(),
+            inner: Some(inner),
+            timeout,
+            deadline: Box::pin(sleep_until(deadline)),
+        }
+    }
+}
+
+impl Stream for TimeoutStream {
+    type Item = DfResult<RecordBatch>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut std::task::Context,
+    ) -> Poll<Option<Self::Item>> {
+        if self.inner.is_none() {
+            return Poll::Ready(None);
+        }
+        // Check deadline first, inner stream may be always ready. The sleep
+        // may not fire on the first poll even if it has expired, so compare the
+        // deadline as well.
+        let expired = self.deadline.as_mut().poll(ctx).is_ready()
+            || Instant::now() >= self.deadline.deadline();
+        if expired {
+            self.inner = None;
+            let err = DataFusionError::Execution(format!("scan timeout after {:?}", self.timeout));
+            return Poll::Ready(Some(Err(err)));
+        }
+
+        let inner = self.inner.as_mut().unwrap();
+        let v = ready!(inner.poll_next_unpin(ctx));
+        if v.is_none() {
+            self.inner = None;
+        }
+        Poll::Ready(v)
+    }
+}
+
+impl RecordBatchStream for TimeoutStream {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
 pub struct ParquetReader {
     store: ObjectStoreRef,
     schema: StorageSchema,
     sst_path_gen: Arc<SstPathGenerator>,
+    max_merge_fan_in: usize,
+    read_bytes_quota: Option<Arc<TokenBucket>>,
 }
 
 impl ParquetReader {
@@ -401,16 +517,27 @@ impl ParquetReader {
         store: ObjectStoreRef,
         schema: StorageSchema,
         sst_path_gen: Arc<SstPathGenerator>,
+        max_merge_fan_in: usize,
     ) -> Self {
         Self {
             store,
             schema,
             sst_path_gen,
+            max_merge_fan_in,
+            read_bytes_quota: None,
         }
     }
 
+    /// Charge bytes read by plans built by it to `quota`.
+    pub fn with_read_bytes_quota(mut self, quota: Option<Arc<TokenBucket>>) -> Self {
+        self.read_bytes_quota = quota;
+        self
+    }
+
     fn build_sort_exprs(&self, df_schema: &DFSchema, sort_seq: bool) -> Result<LexOrdering> {
-        let mut sort_exprs = (0..self.schema.num_primary_keys)
+        // Merge is done by dedup keys, so rows with the same dedup keys should be
+        // ordered by seq.
+        let mut sort_exprs = (0..self.schema.num_dedup_keys)
             .map(|i| {
                 ident(self.schema.arrow_schema.field(i).name())
                     .sort(true /* asc */, true /* nulls_first */)
@@ -426,41 +553,28 @@ impl ParquetReader {
         Ok(sort_exprs)
     }
 
-    pub fn build_df_plan(
+    fn build_scan_plan(
         &self,
-        ssts: Vec<SstFile>,
+        file_groups: Vec<Vec<PartitionedFile>>,
         projection: Option<Vec<usize>>,
-        predicates: Vec<Expr>,
-        keep_builtin: bool,
+        predicate: Option<Arc<dyn PhysicalExpr>>,
+        sort_exprs: &LexOrdering,
     ) -> Result<Arc<dyn ExecutionPlan>> {
         // we won't use url for selecting object_store.
         let dummy_url = ObjectStoreUrl::parse("empty://").unwrap();
-        let df_schema =
-            DFSchema::try_from(self.schema.arrow_schema.clone()).context("build DFSchema")?;
-        let sort_exprs = self.build_sort_exprs(&df_schema, true /* sort_seq */)?;
-
-        let file_groups = ssts
-            .into_iter()
-            .map(|f| {
-                vec![PartitionedFile::new(
-                    self.sst_path_gen.generate(f.id()),
-                    f.meta().size as u64,
-                )]
-            })
-            .collect::<Vec<_>>();
         let scan_config = FileScanConfig::new(dummy_url, self.schema.arrow_schema.clone())
             .with_output_ordering(vec![sort_exprs.clone(); file_groups.len()])
             .with_file_groups(file_groups)
             .with_projection(projection);
 
         let mut builder = ParquetExec::builder(scan_config).with_parquet_file_reader_factory(
-            Arc::new(DefaultParquetFileReaderFactory::new(self.store.clone())),
+            Arc::new(DefaultParquetFileReaderFactory::new(
+                self.store.clone(),
+                self.read_bytes_quota.clone(),
+            )),
         );
-        let base_plan: Arc<dyn ExecutionPlan> = match conjunction(predicates) {
-            Some(expr) => {
-                let filters = create_physical_expr(&expr, &df_schema, &ExecutionProps::new())
-                    .context("create physical expr")?;
-
+        let plan: Arc<dyn ExecutionPlan> = match predicate {
+            Some(filters) => {
                 builder = builder.with_predicate(filters.clone());
                 let parquet_exec = builder.build();
 
@@ -474,6 +588,62 @@ impl ParquetReader {
             }
         };
 
+        Ok(plan)
+    }
+
+    pub fn build_df_plan(
+        &self,
+        ssts: Vec<SstFile>,
+        projection: Option<Vec<usize>>,
+        predicates: Vec<Expr>,
+        keep_builtin: bool,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        let df_schema =
+            DFSchema::try_from(self.schema.arrow_schema.clone()).context("build DFSchema")?;
+        let sort_exprs = self.build_sort_exprs(&df_schema, true /* sort_seq */)?;
+        let predicate = match conjunction(predicates) {
+            Some(expr) => Some(
+                create_physical_expr(&expr, &df_schema, &ExecutionProps::new())
+                    .context("create physical expr")?,
+            ),
+            None => None,
+        };
+
+        let file_groups = ssts
+            .into_iter()
+            .map(|f| {
+                vec![PartitionedFile::new(
+                    self.sst_path_gen.generate(f.id()),
+                    f.meta().size as u64,
+                )]
+            })
+            .collect::<Vec<_>>();
+
+        // Merging too many inputs at once is costly, so when the number of
+        // inputs exceeds the fan-in limit, pre-merge them in groups first.
+        let base_plan: Arc<dyn ExecutionPlan> =
+            if self.max_merge_fan_in > 1 && file_groups.len() > self.max_merge_fan_in {
+                let num_groups = file_groups.len().div_ceil(self.max_merge_fan_in);
+                let group_size = file_groups.len().div_ceil(num_groups);
+                let pre_merged = file_groups
+                    .chunks(group_size)
+                    .map(|groups| {
+                        let plan = self.build_scan_plan(
+                            groups.to_vec(),
+                            projection.clone(),
+                            predicate.clone(),
+                            &sort_exprs,
+                        )?;
+                        let merge_exec = SortPreservingMergeExec::new(sort_exprs.clone(), plan)
+                            .with_round_robin_repartition(true);
+                        Ok(Arc::new(merge_exec) as Arc<dyn ExecutionPlan>)
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Arc::new(UnionExec::new(pre_merged))
+            } else {
+                self.build_scan_plan(file_groups, projection, predicate, &sort_exprs)?
+            };
+
         // TODO: fetch using multiple threads since read from parquet will incur CPU
         // when convert between arrow and parquet.
         let sort_exec =
@@ -481,7 +651,7 @@ impl ParquetReader {
 
         let merge_exec = MergeExec::new(
             Arc::new(sort_exec),
-            self.schema.num_primary_keys,
+            self.schema.num_dedup_keys,
             match self.schema.update_mode {
                 UpdateMode::Overwrite => Arc::new(LastValueOperator),
                 UpdateMode::Append => {
@@ -496,7 +666,10 @@ impl ParquetReader {
 
 #[cfg(test)]
 mod tests {
-    use datafusion::logical_expr::{col, lit};
+    use datafusion::{
+        logical_expr::{col, lit},
+        physical_plan::stream::RecordBatchStreamAdapter,
+    };
     use object_store::local::LocalFileSystem;
     use test_log::test;
 
@@ -572,6 +745,39 @@ mod tests {
         check_stream(Box::pin(stream), expected).await;
     }
 
+    #[tokio::test]
+    async fn test_timeout_stream() {
+        let batch = record_batch!(("pk1", UInt8, vec![1, 2])).unwrap();
+        let schema = batch.schema();
+
+        // Finished before deadline.
+        let inner = futures::stream::iter(vec![Ok(batch.clone())]);
+        let inner = Box::pin(RecordBatchStreamAdapter::new(schema.clone(), inner));
+        let timeout = Duration::from_millis(50);
+        let stream = TimeoutStream::new(inner, timeout, Instant::now() + timeout);
+        let batches = stream.collect::<Vec<_>>().await;
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].as_ref().unwrap(), &batch);
+
+        // Inner stream never finishes.
+        let inner =
+            futures::stream::iter(vec![Ok(batch.clone())]).chain(futures::stream::pending());
+        let inner = Box::pin(RecordBatchStreamAdapter::new(schema.clone(), inner));
+        let mut stream = TimeoutStream::new(inner, timeout, Instant::now() + timeout);
+        assert!(stream.next().await.unwrap().is_ok());
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("scan timeout"), "{err}");
+        assert!(stream.next().await.is_none());
+
+        // Inner stream is always ready, but deadline has passed.
+        let inner = futures::stream::repeat_with(move || Ok(batch.clone()));
+        let inner = Box::pin(RecordBatchStreamAdapter::new(schema, inner));
+        let mut stream = TimeoutStream::new(inner, timeout, Instant::now());
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("scan timeout"), "{err}");
+        assert!(stream.next().await.is_none());
+    }
+
     #[tokio::test]
     async fn test_build_scan_plan() {
         let schema = arrow_schema!(("pk1", UInt8), ("value", UInt8));
@@ -580,6 +786,7 @@ mod tests {
             store,
             StorageSchema::try_new(schema, 1, UpdateMode::Overwrite).unwrap(),
             Arc::new(SstPathGenerator::new("mock".to_string())),
+            64, // max_merge_fan_in
         );
 
         let expr = col("pk1").eq(lit(0_u8));
@@ -611,6 +818,53 @@ mod tests {
   SortPreservingMergeExec: [pk1@0 ASC, __seq__@2 ASC]
     FilterExec: pk1@0 = 0
       ParquetExec: file_groups={3 groups: [[mock/data/100.sst], [mock/data/101.sst], [mock/data/102.sst]]}, projection=[pk1, value, __seq__, __reserved__], output_orderings=[[pk1@0 ASC, __seq__@2 ASC], [pk1@0 ASC, __seq__@2 ASC], [pk1@0 ASC, __seq__@2 ASC]], predicate=pk1@0 = 0, pruning_predicate=CASE WHEN pk1_null_count@2 = pk1_row_count@3 THEN false ELSE pk1_min@0 <= 0 AND 0 <= pk1_max@1 END, required_guarantees=[pk1 in (0)]
+"#,
+            format!("{display_plan}")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_scan_plan_with_fan_in_limit() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", UInt8));
+        let store = Arc::new(LocalFileSystem::new());
+        let reader = ParquetReader::new(
+            store,
+            StorageSchema::try_new(schema, 1, UpdateMode::Overwrite).unwrap(),
+            Arc::new(SstPathGenerator::new("mock".to_string())),
+            2, // max_merge_fan_in
+        );
+
+        let plan = reader
+            .build_df_plan(
+                (100..103)
+                    .map(|id| {
+                        SstFile::new(
+                            id,
+                            FileMeta {
+                                max_sequence: id,
+                                num_rows: 1,
+                                size: 1,
+                                time_range: (1..10).into(),
+                            },
+                        )
+                    })
+                    .collect(),
+                None,
+                vec![],
+                false, // keep_builtin
+            )
+            .unwrap();
+        let display_plan =
+            datafusion::physical_plan::display::DisplayableExecutionPlan::new(plan.as_ref())
+                .indent(true);
+        assert_eq!(
+            r#"MergeExec: [primary_keys: 1, keep_builtin: false]
+  SortPreservingMergeExec: [pk1@0 ASC, __seq__@2 ASC]
+    UnionExec
+      SortPreservingMergeExec: [pk1@0 ASC, __seq__@2 ASC]
+        ParquetExec: file_groups={2 groups: [[mock/data/100.sst], [mock/data/101.sst]]}, projection=[pk1, value, __seq__, __reserved__], output_orderings=[[pk1@0 ASC, __seq__@2 ASC], [pk1@0 ASC, __seq__@2 ASC]]
+      SortPreservingMergeExec: [pk1@0 ASC, __seq__@2 ASC]
+        ParquetExec: file_groups={1 group: [[mock/data/102.sst]]}, projection=[pk1, value, __seq__, __reserved__], output_ordering=[pk1@0 ASC, __seq__@2 ASC]
 "#,
             format!("{display_plan}")
         );
@@ -15,46 +15,90 @@
 // specific language governing permissions and limitations
 // under the License.
 
-use std::{sync::Arc, time::Duration, vec};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    sync::Arc,
+    time::Duration,
+    vec,
+};
 
 use anyhow::Context;
-use arrow::{array::RecordBatch, datatypes::SchemaRef};
+use arrow::{
+    array::{Array, AsArray, RecordBatch},
+    compute::{cast, concat_batches, max, min},
+    datatypes::{DataType, Int64Type, Schema, SchemaRef, TimeUnit},
+    ipc::{reader::FileReader, writer::FileWriter},
+};
 use async_trait::async_trait;
+use common::ReadableSize;
 use datafusion::{
     self,
     common::DFSchema,
-    execution::{context::ExecutionProps, SendableRecordBatchStream},
+    execution::{
+        context::ExecutionProps,
+        disk_manager::{DiskManager, DiskManagerConfig, RefCountedTempFile},
+        SendableRecordBatchStream, TaskContext,
+    },
     logical_expr::Expr,
     physical_expr::LexOrdering,
     physical_plan::{
-        execute_stream, memory::MemoryExec, sorts::sort::SortExec, union::UnionExec,
-        EmptyRecordBatchStream,
+        coalesce_partitions::CoalescePartitionsExec,
+        execute_stream,
+        limit::{GlobalLimitExec, LocalLimitExec},
+        memory::MemoryExec,
+        sorts::{
+            sort::{sort_batch, SortExec},
+            sort_preserving_merge::SortPreservingMergeExec,
+        },
+        stream::RecordBatchReceiverStream,
+        streaming::{PartitionStream, StreamingTableExec},
+        union::UnionExec,
+        EmptyRecordBatchStream, ExecutionPlan, ExecutionPlanProperties,
     },
     physical_planner::create_physical_sort_exprs,
     prelude::{ident, SessionContext},
 };
-use futures::StreamExt;
+use futures::{future::join_all, StreamExt, TryStreamExt};
 use itertools::Itertools;
 use object_store::path::Path;
 use parquet::{
-    arrow::{async_writer::ParquetObjectWriter, AsyncArrowWriter},
+    arrow::{
+        async_reader::{ParquetObjectReader, ParquetRecordBatchStreamBuilder},
+        async_writer::ParquetObjectWriter,
+        AsyncArrowWriter,
+    },
     file::properties::WriterProperties,
     format::SortingColumn,
     schema::types::ColumnPath,
 };
-use tokio::runtime::Runtime;
+use serde::Serialize;
+use tokio::{
+    runtime::Runtime,
+    sync::{Mutex, RwLock},
+    time::Instant,
+};
+use tracing::{debug, error, info};
 
+pub use crate::compaction::{CompactionPhase, CompactionTaskStatus};
 use crate::{
     compaction::CompactionScheduler,
     config::{StorageConfig, WriteConfig},
     ensure,
-    manifest::{Manifest, ManifestRef},
-    read::ParquetReader,
-    sst::{FileMeta, SstFile, SstPathGenerator},
+    manifest::{
+        dump_manifest, write_snapshot, Manifest, ManifestDump, ManifestObserverRef, ManifestRef,
+        ManifestSummary, ManifestUpdate, SstStatus,
+    },
+    read::{ParquetReader, TimeoutStream},
+    sst::{FileId, FileMeta, SstFile, SstPathGenerator},
+    store::TokenBucket,
     types::{ObjectStoreRef, StorageSchema, TimeRange, WriteResult, SEQ_COLUMN_NAME},
-    Result,
+    AnyhowError, Error, Result,
 };
 
+// Number of rows per batch when writing sorted runs to spill files.
+const SPILL_BATCH_ROWS: usize = 8192;
+
 pub struct WriteRequest {
     pub batch: RecordBatch,
     pub time_range: TimeRange,
@@ -67,11 +111,51 @@ pub struct ScanRequest {
     pub predicate: Vec<Expr>,
     /// `None` means all columns.
     pub projections: Option<Vec<usize>>,
+    /// Scan fails once it runs longer than this, counted from the call of
+    /// `scan`. `None` means no timeout.
+    ///
+    /// Dropping the returned stream cancels the scan as well.
+    pub timeout: Option<Duration>,
+    /// Return at most `limit` rows, inputs stop being read once it's reached.
+    pub limit: Option<usize>,
 }
 
 #[derive(Default)]
 pub struct CompactRequest {}
 
+/// A pre-built parquet file to ingest, its schema should be the same as the
+/// storage's, and all its rows should be in `time_range`.
+pub struct IngestFile {
+    /// Path in the storage's object store.
+    pub path: String,
+    pub time_range: TimeRange,
+    /// Column holding the timestamp in milliseconds of each row, ingest fails
+    /// if any row is out of `time_range`. It's only used for checking, and
+    /// is not written to the sst.
+    ///
+    /// `None` means `time_range` is trusted as is.
+    pub time_column: Option<String>,
+}
+
+pub struct IngestRequest {
+    pub files: Vec<IngestFile>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ExportSnapshotResult {
+    /// Ssts copied, ordered by id.
+    pub ssts: Vec<FileId>,
+    pub total_size: u64,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct DeleteRangeResult {
+    /// Ssts fully covered by the range, they are removed.
+    pub deleted: Vec<FileId>,
+    /// Ssts being compacted, they are kept, and the caller could retry later.
+    pub skipped: Vec<FileId>,
+}
+
 /// Time-aware merge storage interface.
 #[async_trait]
 pub trait TimeMergeStorage {
@@ -84,6 +168,63 @@ pub trait TimeMergeStorage {
     async fn scan(&self, req: ScanRequest) -> Result<SendableRecordBatchStream>;
 
     async fn compact(&self, req: CompactRequest) -> Result<()>;
+
+    /// Load pre-built parquet files as new ssts, returns their ids in order.
+    ///
+    /// Files are sorted and rewritten as ssts, then added to manifest in one
+    /// update, so either all or none of them are visible. Source files are
+    /// kept.
+    async fn ingest(&self, req: IngestRequest) -> Result<Vec<FileId>>;
+
+    /// Remove ssts whose time range is fully covered by `range`.
+    ///
+    /// Rows are only tracked by the time range of their sst, there is no way to
+    /// tell which rows of a partially covered sst are in `range`, so it fails
+    /// without removing anything if any sst is partially covered.
+    async fn delete_range(&self, range: TimeRange) -> Result<DeleteRangeResult>;
+
+    /// Copy current ssts and a manifest snapshot describing them to
+    /// `dest_prefix` in the same object store, the copy could be opened as a
+    /// storage directly.
+    ///
+    /// Compaction is paused and ssts are protected from `delete_range` while
+    /// copying, so ssts won't be removed halfway. Concurrent exports run one
+    /// by one, and it fails if any sst is being deleted by `delete_range`,
+    /// which could be retried later.
+    async fn export_snapshot(&self, dest_prefix: &str) -> Result<ExportSnapshotResult>;
+
+    /// Stop scheduling new compaction tasks for this storage, tasks already
+    /// running are not affected.
+    ///
+    /// The pause is not persisted, see `SchedulerConfig::paused` for how to
+    /// open a storage paused.
+    fn pause_compaction(&self);
+
+    fn resume_compaction(&self);
+
+    fn is_compaction_paused(&self) -> bool;
+
+    /// Returns compaction tasks which are queued or running.
+    fn compaction_tasks(&self) -> Vec<CompactionTaskStatus>;
+
+    /// Merge manifest deltas into snapshot now, which makes next open faster.
+    async fn merge_manifest(&self) -> Result<()>;
+
+    async fn dump_manifest(&self) -> Result<ManifestDump>;
+
+    /// Returns current ssts ordered by time range.
+    async fn list_ssts(&self) -> Vec<SstStatus>;
+
+    async fn manifest_summary(&self) -> ManifestSummary;
+
+    /// Register an observer to be notified of sst files added or deleted.
+    fn register_manifest_observer(&self, observer: ManifestObserverRef);
+
+    /// Reject new writes, wait for running writes and compactions to finish,
+    /// then merge manifest deltas so next open is fast.
+    ///
+    /// Writes after close will fail, calling it more than once is a no-op.
+    async fn close(&self) -> Result<()>;
 }
 
 pub type TimeMergeStorageRef = Arc<(dyn TimeMergeStorage + Send + Sync)>;
@@ -116,10 +257,22 @@ pub struct CloudObjectStorage {
     schema: StorageSchema,
     manifest: ManifestRef,
     runtimes: StorageRuntimes,
+    // Used by scan, bytes read are charged to `scan_bytes_quota`.
     parquet_reader: Arc<ParquetReader>,
     write_props: WriterProperties,
+    sort_memory_limit: Option<ReadableSize>,
+    sort_disk_manager: Arc<DiskManager>,
     sst_path_gen: Arc<SstPathGenerator>,
     compact_scheduler: CompactionScheduler,
+    // See `QuotaConfig`.
+    write_rows_quota: Option<TokenBucket>,
+    scan_bytes_quota: Option<Arc<TokenBucket>>,
+    // Writes hold the read lock, so close can wait for them by taking the write
+    // lock.
+    closed: RwLock<bool>,
+    // Exports claim all ssts, so they are serialized, otherwise one would see
+    // ssts claimed by the other.
+    export_lock: Mutex<()>,
 }
 
 /// It will organize the data in the following way:
@@ -143,8 +296,12 @@ impl CloudObjectStorage {
         storage_opts: StorageConfig,
         runtimes: StorageRuntimes,
     ) -> Result<Self> {
-        let schema =
+        storage_opts.validate()?;
+        let mut schema =
             StorageSchema::try_new(arrow_schema, num_primary_keys, storage_opts.update_mode)?;
+        if let Some(num_dedup_keys) = storage_opts.num_dedup_keys {
+            schema = schema.with_num_dedup_keys(num_dedup_keys)?;
+        }
         let manifest = Manifest::try_new(
             path.clone(),
             store.clone(),
@@ -153,13 +310,30 @@ impl CloudObjectStorage {
         )
         .await?;
         let manifest = Arc::new(manifest);
-        let write_props = Self::build_write_props(storage_opts.write, num_primary_keys);
+        let sort_memory_limit = storage_opts.write.sort_memory_limit;
+        let write_rows_quota = storage_opts.quota.write_rows_per_sec.map(TokenBucket::new);
+        let scan_bytes_quota = storage_opts
+            .quota
+            .scan_bytes_per_sec
+            .map(|v| Arc::new(TokenBucket::new(v.as_byte())));
+        let sort_disk_manager = DiskManager::try_new(match &storage_opts.write.sort_spill_dir {
+            Some(dir) => DiskManagerConfig::NewSpecified(vec![dir.into()]),
+            None => DiskManagerConfig::NewOs,
+        })
+        .context("create sort disk manager")?;
+        let write_props = Self::build_write_props(storage_opts.write, &schema)?;
         let sst_path_gen = Arc::new(SstPathGenerator::new(path.clone()));
-        let parquet_reader = Arc::new(ParquetReader::new(
-            store.clone(),
-            schema.clone(),
-            sst_path_gen.clone(),
-        ));
+        let new_parquet_reader = || {
+            ParquetReader::new(
+                store.clone(),
+                schema.clone(),
+                sst_path_gen.clone(),
+                storage_opts.scan.max_merge_fan_in,
+            )
+        };
+        let compact_parquet_reader = Arc::new(new_parquet_reader());
+        let parquet_reader =
+            Arc::new(new_parquet_reader().with_read_bytes_quota(scan_bytes_quota.clone()));
         let compact_scheduler = CompactionScheduler::new(
             runtimes.sst_compact_runtime.clone(),
             manifest.clone(),
@@ -167,7 +341,7 @@ impl CloudObjectStorage {
             schema.clone(),
             segment_duration,
             sst_path_gen.clone(),
-            parquet_reader.clone(),
+            compact_parquet_reader,
             storage_opts.scheduler,
             write_props.clone(),
         );
@@ -180,12 +354,255 @@ impl CloudObjectStorage {
             parquet_reader,
             runtimes,
             write_props,
+            sort_memory_limit,
+            sort_disk_manager,
             sst_path_gen,
             compact_scheduler,
+            write_rows_quota,
+            scan_bytes_quota,
+            closed: RwLock::new(false),
+            export_lock: Mutex::new(()),
         })
     }
 
+    async fn scan_inner(&self, mut req: ScanRequest) -> Result<SendableRecordBatchStream> {
+        let total_ssts = self.manifest.find_ssts(&req.range).await;
+        if total_ssts.is_empty() {
+            return Ok(Box::pin(EmptyRecordBatchStream::new(
+                self.schema.arrow_schema.clone(),
+            )));
+        }
+        // Bytes are charged while reading, see `ChargedFileReader`, so only
+        // check whether the quota is used up by previous scans.
+        if let Some(quota) = &self.scan_bytes_quota {
+            if !quota.try_reserve(0) {
+                return Err(Error::QuotaExceeded(
+                    "scan_bytes_per_sec is used up".to_string(),
+                ));
+            }
+        }
+
+        let ssts_by_segment = total_ssts.into_iter().group_by(|file| {
+            file.meta().time_range.start.0 / self.segment_duration.as_millis() as i64
+        });
+
+        let mut plan_for_all_segments = Vec::new();
+        self.schema.fill_required_projections(&mut req.projections);
+        for (_, ssts) in ssts_by_segment.sorted_by(|a, b| a.0.cmp(&b.0)) {
+            let plan = self.parquet_reader.build_df_plan(
+                ssts,
+                req.projections.clone(),
+                req.predicate.clone(),
+                false, // keep_builtin
+            )?;
+
+            plan_for_all_segments.push(plan);
+        }
+
+        let mut plan: Arc<dyn ExecutionPlan> = if plan_for_all_segments.len() == 1 {
+            plan_for_all_segments.remove(0)
+        } else {
+            Arc::new(UnionExec::new(plan_for_all_segments))
+        };
+        if let Some(limit) = req.limit {
+            // Limit each partition first, so every merge stops early.
+            plan = Arc::new(LocalLimitExec::new(plan, limit));
+            if plan.output_partitioning().partition_count() > 1 {
+                plan = Arc::new(CoalescePartitionsExec::new(plan));
+            }
+            plan = Arc::new(GlobalLimitExec::new(plan, 0, Some(limit)));
+        }
+
+        let ctx = SessionContext::default();
+        let res = execute_stream(plan, ctx.task_ctx()).context("execute stream")?;
+        Ok(res)
+    }
+
+    async fn export_snapshot_inner(
+        &self,
+        dest_prefix: &str,
+        ssts: &[SstFile],
+    ) -> Result<ExportSnapshotResult> {
+        let dest_path_gen = SstPathGenerator::new(dest_prefix.to_string());
+        let total = ssts.len();
+        let mut result = ExportSnapshotResult::default();
+        for (i, sst) in ssts.iter().enumerate() {
+            let from = Path::from(self.sst_path_gen.generate(sst.id()));
+            let to = Path::from(dest_path_gen.generate(sst.id()));
+            self.store
+                .copy(&from, &to)
+                .await
+                .with_context(|| format!("copy sst from {from} to {to}"))?;
+            result.ssts.push(sst.id());
+            result.total_size += sst.size() as u64;
+            debug!(copied = i + 1, total, "Export snapshot in progress");
+        }
+        write_snapshot(&self.store, dest_prefix, ssts.to_vec()).await?;
+        info!(
+            dest_prefix,
+            num_ssts = total,
+            total_size = result.total_size,
+            "Export snapshot finished"
+        );
+
+        Ok(result)
+    }
+
+    fn check_time_range(&self, time_range: &TimeRange) -> Result<()> {
+        let segment_duration = self.segment_duration.as_millis() as i64;
+        ensure!(
+            time_range.start.0 / segment_duration == (time_range.end.0 - 1) / segment_duration,
+            "time range can't cross segment, value:{:?}",
+            time_range
+        );
+        Ok(())
+    }
+
+    /// Rewrite the parquet file as a sst, it's not added to manifest yet.
+    ///
+    /// The file is read in batches, which are sorted and spilled in runs once
+    /// they exceed half of `sort_memory_limit`, so the whole file is only held
+    /// in memory when the limit is not set.
+    async fn ingest_file(&self, file: IngestFile) -> Result<SstFile> {
+        self.check_time_range(&file.time_range)?;
+        let path = Path::from(file.path.as_str());
+        let object_meta = self
+            .store
+            .head(&path)
+            .await
+            .with_context(|| format!("get object meta, path:{path}"))?;
+        let reader = ParquetObjectReader::new(self.store.clone(), object_meta);
+        let builder = ParquetRecordBatchStreamBuilder::new(reader)
+            .await
+            .with_context(|| format!("read parquet metadata, path:{path}"))?;
+
+        // Builtin columns are filled when writing.
+        let fields = self
+            .schema()
+            .fields()
+            .iter()
+            .filter(|f| !StorageSchema::is_builtin_field(f))
+            .cloned()
+            .collect::<Vec<_>>();
+        let time_idx = match &file.time_column {
+            Some(name) => {
+                let (idx, field) = builder
+                    .schema()
+                    .column_with_name(name)
+                    .with_context(|| format!("time column {name} not found in {path}"))?;
+                ensure!(
+                    matches!(
+                        field.data_type(),
+                        DataType::Int64 | DataType::Timestamp(TimeUnit::Millisecond, _)
+                    ),
+                    "time column {name} of {path} should be Int64 or Timestamp(Millisecond), actual:{}",
+                    field.data_type()
+                );
+                Some(idx)
+            }
+            None => None,
+        };
+        let projection = (0..builder.schema().fields().len())
+            .filter(|i| Some(*i) != time_idx)
+            .collect::<Vec<_>>();
+        let file_fields = builder
+            .schema()
+            .project(&projection)
+            .context("project schema")?;
+        let file_fields = file_fields.fields();
+        ensure!(
+            fields.len() == file_fields.len()
+                && fields
+                    .iter()
+                    .zip(file_fields.iter())
+                    .all(|(a, b)| { a.name() == b.name() && a.data_type() == b.data_type() }),
+            "schema of {path} mismatch, expected:{fields:?}, actual:{file_fields:?}"
+        );
+
+        let schema = Arc::new(Schema::new(fields));
+        let df_schema = DFSchema::try_from(self.schema().clone()).context("build DFSchema")?;
+        let sort_exprs = self.build_sort_exprs(&df_schema, false /* sort_seq */)?;
+        let run_limit = self
+            .sort_memory_limit
+            .map(|limit| limit.as_byte() as usize / 2);
+        let mut stream = builder
+            .build()
+            .with_context(|| format!("build parquet reader, path:{path}"))?;
+        let mut num_rows = 0;
+        // Min and max timestamp of rows read.
+        let mut time_bound: Option<(i64, i64)> = None;
+        let mut buffered = Vec::new();
+        let mut buffered_size = 0;
+        let mut runs = Vec::new();
+        while let Some(batch) = stream
+            .try_next()
+            .await
+            .with_context(|| format!("read parquet, path:{path}"))?
+        {
+            if let Some(idx) = time_idx {
+                let times =
+                    cast(batch.column(idx), &DataType::Int64).context("cast time column")?;
+                let times = times.as_primitive::<Int64Type>();
+                ensure!(
+                    times.null_count() == 0,
+                    "time column of {path} contains null"
+                );
+                if let (Some(min), Some(max)) = (min(times), max(times)) {
+                    time_bound = Some(match time_bound {
+                        Some((lo, hi)) => (lo.min(min), hi.max(max)),
+                        None => (min, max),
+                    });
+                }
+            }
+            let batch = batch.project(&projection).context("project batch")?;
+            let batch = RecordBatch::try_new(schema.clone(), batch.columns().to_vec())
+                .context("convert batch to storage schema")?;
+            num_rows += batch.num_rows();
+            buffered_size += batch.get_array_memory_size();
+            buffered.push(batch);
+            if run_limit.is_some_and(|limit| buffered_size > limit) {
+                let run = concat_batches(&schema, buffered.iter()).context("concat batches")?;
+                buffered.clear();
+                buffered_size = 0;
+                runs.push(self.spill_sorted_run(run, &sort_exprs).await?);
+            }
+        }
+        if let Some((min, max)) = time_bound {
+            let time_range = &file.time_range;
+            ensure!(
+                time_range.start.0 <= min && max < time_range.end.0,
+                "rows of {path} are out of time range, expected:{time_range:?}, actual:[{min}, {max}]"
+            );
+        }
+
+        let batch = concat_batches(&schema, buffered.iter()).context("concat batches")?;
+        drop(buffered);
+        let sorted = if runs.is_empty() {
+            self.sort_batch(batch).await?
+        } else {
+            if batch.num_rows() > 0 {
+                runs.push(self.spill_sorted_run(batch, &sort_exprs).await?);
+            }
+            self.merge_sorted_runs(schema, runs, sort_exprs)?
+        };
+        let WriteResult { id, seq, size } = self.write_sorted(sorted).await?;
+        let file_meta = FileMeta {
+            max_sequence: seq,
+            num_rows: num_rows as u32,
+            size: size as u32,
+            time_range: file.time_range,
+        };
+
+        Ok(SstFile::new(id, file_meta))
+    }
+
     async fn write_batch(&self, batch: RecordBatch) -> Result<WriteResult> {
+        let batches = self.sort_batch(batch).await?;
+        self.write_sorted(batches).await
+    }
+
+    /// Write batches sorted by primary keys to a new sst.
+    async fn write_sorted(&self, mut batches: SendableRecordBatchStream) -> Result<WriteResult> {
         let file_id = SstFile::allocate_id();
         let file_path = self.sst_path_gen.generate(file_id);
         let file_path = Path::from(file_path);
@@ -197,8 +614,6 @@ impl CloudObjectStorage {
         )
         .context("create arrow writer")?;
 
-        // sort record batch
-        let mut batches = self.sort_batch(batch).await?;
         while let Some(batch) = batches.next().await {
             let batch = batch.context("get sorted batch")?;
             // Since file_id is increasing order, we can use it as sequence.
@@ -245,18 +660,118 @@ impl CloudObjectStorage {
         let schema = batch.schema();
         let df_schema = DFSchema::try_from(self.schema().clone()).context("build DFSchema")?;
         let sort_exprs = self.build_sort_exprs(&df_schema, false /* sort_seq */)?;
-        let batch_plan =
-            MemoryExec::try_new(&[vec![batch]], schema, None).context("build batch plan")?;
-        let physical_plan = Arc::new(SortExec::new(sort_exprs, Arc::new(batch_plan)));
+        let batch_size = batch.get_array_memory_size();
+        let Some(memory_limit) = self
+            .sort_memory_limit
+            .map(|limit| limit.as_byte() as usize)
+            .filter(|limit| batch_size > *limit)
+        else {
+            let batch_plan =
+                MemoryExec::try_new(&[vec![batch]], schema, None).context("build batch plan")?;
+            let physical_plan = Arc::new(SortExec::new(sort_exprs, Arc::new(batch_plan)));
+            let res = execute_stream(physical_plan, ctx.task_ctx())
+                .context("execute sort physical plan")?;
+            return Ok(res);
+        };
+
+        // External merge sort: sort the batch in runs bounded by the memory limit,
+        // spill each sorted run to disk, then merge all runs.
+        // Each run takes at most half of the limit, the other half is for its sorted
+        // copy.
+        let run_rows = (batch.num_rows() * (memory_limit / 2) / batch_size).max(1);
+        let mut runs = Vec::new();
+        for offset in (0..batch.num_rows()).step_by(run_rows) {
+            let run = batch.slice(offset, run_rows.min(batch.num_rows() - offset));
+            runs.push(self.spill_sorted_run(run, &sort_exprs).await?);
+        }
+        drop(batch);
+        debug!(
+            batch_size,
+            memory_limit,
+            num_runs = runs.len(),
+            "Sort batch exceeding memory limit by spilling"
+        );
+
+        self.merge_sorted_runs(schema, runs, sort_exprs)
+    }
 
+    /// Merge sorted runs spilled by `spill_sorted_run` into one sorted stream.
+    fn merge_sorted_runs(
+        &self,
+        schema: SchemaRef,
+        runs: Vec<RefCountedTempFile>,
+        sort_exprs: LexOrdering,
+    ) -> Result<SendableRecordBatchStream> {
+        let ctx = SessionContext::default();
+        let partitions = runs
+            .into_iter()
+            .map(|file| {
+                Arc::new(SpilledRunStream {
+                    schema: schema.clone(),
+                    file: Arc::new(file),
+                }) as Arc<dyn PartitionStream>
+            })
+            .collect();
+        let runs_plan = StreamingTableExec::try_new(
+            schema,
+            partitions,
+            None,
+            vec![sort_exprs.clone()],
+            false, // infinite
+            None,  // limit
+        )
+        .context("build sorted runs plan")?;
+        let physical_plan = Arc::new(SortPreservingMergeExec::new(
+            sort_exprs,
+            Arc::new(runs_plan),
+        ));
         let res =
-            execute_stream(physical_plan, ctx.task_ctx()).context("execute sort physical plan")?;
+            execute_stream(physical_plan, ctx.task_ctx()).context("execute merge physical plan")?;
         Ok(res)
     }
 
-    fn build_write_props(write_options: WriteConfig, num_primary_key: usize) -> WriterProperties {
+    /// Sort and write the run to a temp file, it's done in blocking threads
+    /// since local disk is accessed by std::fs.
+    async fn spill_sorted_run(
+        &self,
+        run: RecordBatch,
+        sort_exprs: &LexOrdering,
+    ) -> Result<RefCountedTempFile> {
+        let disk_manager = self.sort_disk_manager.clone();
+        let sort_exprs = sort_exprs.clone();
+        tokio::task::spawn_blocking(move || {
+            let sorted = sort_batch(&run, &sort_exprs, None).context("sort run")?;
+            drop(run);
+            let file = disk_manager
+                .create_tmp_file("sort batch")
+                .context("create spill file")?;
+            {
+                let mut writer =
+                    FileWriter::try_new(BufWriter::new(file.inner().as_file()), &sorted.schema())
+                        .context("create spill writer")?;
+                // Write in small batches, so merging only needs to read a small part
+                // of each run at a time.
+                for offset in (0..sorted.num_rows()).step_by(SPILL_BATCH_ROWS) {
+                    let len = SPILL_BATCH_ROWS.min(sorted.num_rows() - offset);
+                    writer
+                        .write(&sorted.slice(offset, len))
+                        .context("write spill file")?;
+                }
+                writer.finish().context("finish spill file")?;
+            }
+
+            Ok(file)
+        })
+        .await
+        .context("join spill task")?
+    }
+
+    fn build_write_props(
+        write_options: WriteConfig,
+        schema: &StorageSchema,
+    ) -> Result<WriterProperties> {
         let sorting_columns = write_options.enable_sorting_columns.then(|| {
-            (0..num_primary_key)
+            (0..schema.num_primary_keys)
                 .map(|i| {
                     SortingColumn::new(i as i32, false /* desc */, true /* nulls_first */)
                 })
@@ -270,10 +785,22 @@ impl CloudObjectStorage {
             .set_dictionary_enabled(write_options.enable_dict)
             .set_bloom_filter_enabled(write_options.enable_bloom_filter)
             .set_encoding(write_options.encoding.into())
-            .set_compression(write_options.compression.into());
+            .set_compression(
+                write_options
+                    .compression
+                    .clone()
+                    .with_level(write_options.compression_level)?,
+            );
+
+        if write_options.enable_primary_key_bloom_filter {
+            for i in 0..schema.num_primary_keys {
+                let col_path = ColumnPath::new(vec![schema.arrow_schema.field(i).name().clone()]);
+                builder = builder.set_column_bloom_filter_enabled(col_path, true);
+            }
+        }
 
         if write_options.column_options.is_none() {
-            return builder.build();
+            return Ok(builder.build());
         }
 
         for (col_name, col_opt) in write_options.column_options.unwrap() {
@@ -288,11 +815,49 @@ impl CloudObjectStorage {
             if let Some(encoding) = col_opt.encoding {
                 builder = builder.set_column_encoding(col_path.clone(), encoding.into());
             }
-            if let Some(compression) = col_opt.compression {
-                builder = builder.set_column_compression(col_path, compression.into());
+            if col_opt.compression.is_some() || col_opt.compression_level.is_some() {
+                // Fallback to the default codec when only level is specified.
+                let compression = col_opt
+                    .compression
+                    .unwrap_or_else(|| write_options.compression.clone())
+                    .with_level(col_opt.compression_level)
+                    .with_context(|| format!("build compression for column {col_name}"))?;
+                builder = builder.set_column_compression(col_path, compression);
             }
         }
 
+        Ok(builder.build())
+    }
+}
+
+/// Reads a sorted run spilled by `CloudObjectStorage::sort_batch`.
+#[derive(Debug)]
+struct SpilledRunStream {
+    schema: SchemaRef,
+    file: Arc<RefCountedTempFile>,
+}
+
+impl PartitionStream for SpilledRunStream {
+    fn schema(&self) -> &SchemaRef {
+        &self.schema
+    }
+
+    fn execute(&self, _ctx: Arc<TaskContext>) -> SendableRecordBatchStream {
+        // Read in blocking threads, only a few batches are buffered.
+        let mut builder = RecordBatchReceiverStream::builder(self.schema.clone(), 2);
+        let tx = builder.tx();
+        let file = self.file.clone();
+        builder.spawn_blocking(move || {
+            let file = File::open(file.path())?;
+            let reader = FileReader::try_new(BufReader::new(file), None)?;
+            for batch in reader {
+                if tx.blocking_send(batch.map_err(Into::into)).is_err() {
+                    // Receiver is dropped, no need to read more.
+                    break;
+                }
+            }
+            Ok(())
+        });
         builder.build()
     }
 }
@@ -304,17 +869,20 @@ impl TimeMergeStorage for CloudObjectStorage {
     }
 
     async fn write(&self, req: WriteRequest) -> Result<()> {
+        let closed = self.closed.read().await;
+        ensure!(!*closed, "storage is closed");
         if req.enable_check {
-            let segment_duration = self.segment_duration.as_millis() as i64;
-            ensure!(
-                req.time_range.start.0 / segment_duration
-                    == (req.time_range.end.0 - 1) / segment_duration,
-                "time range can't cross segment, value:{:?}",
-                &req.time_range
-            );
+            self.check_time_range(&req.time_range)?;
         }
 
         let num_rows = req.batch.num_rows();
+        if let Some(quota) = &self.write_rows_quota {
+            if !quota.try_reserve(num_rows as u64) {
+                return Err(Error::QuotaExceeded(format!(
+                    "write_rows_per_sec is used up, num_rows:{num_rows}"
+                )));
+            }
+        }
         let WriteResult {
             id: file_id,
             seq,
@@ -331,56 +899,263 @@ impl TimeMergeStorage for CloudObjectStorage {
         Ok(())
     }
 
-    async fn scan(&self, mut req: ScanRequest) -> Result<SendableRecordBatchStream> {
-        let total_ssts = self.manifest.find_ssts(&req.range).await;
-        if total_ssts.is_empty() {
-            return Ok(Box::pin(EmptyRecordBatchStream::new(
-                self.schema.arrow_schema.clone(),
-            )));
+    async fn scan(&self, req: ScanRequest) -> Result<SendableRecordBatchStream> {
+        let Some(timeout) = req.timeout else {
+            return self.scan_inner(req).await;
+        };
+
+        let deadline = Instant::now() + timeout;
+        let stream = tokio::time::timeout_at(deadline, self.scan_inner(req))
+            .await
+            .map_err(|_| AnyhowError::msg(format!("scan timeout after {timeout:?}")))??;
+        Ok(Box::pin(TimeoutStream::new(stream, timeout, deadline)))
+    }
+
+    async fn compact(&self, _req: CompactRequest) -> Result<()> {
+        self.compact_scheduler.trigger_compaction()
+    }
+
+    async fn ingest(&self, req: IngestRequest) -> Result<Vec<FileId>> {
+        let closed = self.closed.read().await;
+        ensure!(!*closed, "storage is closed");
+
+        let mut to_adds = Vec::with_capacity(req.files.len());
+        let mut res = Ok(());
+        for file in req.files {
+            match self.ingest_file(file).await {
+                Ok(sst) => to_adds.push(sst),
+                Err(e) => {
+                    res = Err(e);
+                    break;
+                }
+            }
+        }
+        let ids = to_adds.iter().map(|f| f.id()).collect::<Vec<_>>();
+        if res.is_ok() {
+            res = self
+                .manifest
+                .update(ManifestUpdate::new(to_adds, vec![]))
+                .await;
+        }
+        if let Err(e) = res {
+            // Ssts written are not visible, remove them.
+            for id in ids {
+                let path = Path::from(self.sst_path_gen.generate(id));
+                if let Err(e) = self.store.delete(&path).await {
+                    error!(id, "Failed to delete sst, err:{e}");
+                }
+            }
+            return Err(e);
         }
+        info!(?ids, "Ingest finished");
 
-        let ssts_by_segment = total_ssts.into_iter().group_by(|file| {
-            file.meta().time_range.start.0 / self.segment_duration.as_millis() as i64
-        });
+        Ok(ids)
+    }
 
-        let mut plan_for_all_segments = Vec::new();
-        self.schema.fill_required_projections(&mut req.projections);
-        for (_, ssts) in ssts_by_segment.sorted_by(|a, b| a.0.cmp(&b.0)) {
-            let plan = self.parquet_reader.build_df_plan(
-                ssts,
-                req.projections.clone(),
-                req.predicate.clone(),
-                false, // keep_builtin
-            )?;
+    async fn export_snapshot(&self, dest_prefix: &str) -> Result<ExportSnapshotResult> {
+        let _export_guard = self.export_lock.lock().await;
+        let _pause_guard = self.compact_scheduler.pause_until_idle().await;
+        // Claim ssts so they won't be removed by `delete_range` while copying.
+        // Compaction is idle and other exports are waiting for the lock, so ssts
+        // claimed already are being deleted by `delete_range`, fail instead of
+        // exporting an incomplete snapshot.
+        let mut ssts = self.manifest.all_ssts().await;
+        ssts.sort_by_key(|f| f.id());
+        for (i, sst) in ssts.iter().enumerate() {
+            if !sst.try_mark_compaction() {
+                for claimed in &ssts[..i] {
+                    claimed.unmark_compaction();
+                }
+                return Err(AnyhowError::msg(format!(
+                    "sst {} is being deleted, retry export later",
+                    sst.id()
+                ))
+                .into());
+            }
+        }
+        let res = self.export_snapshot_inner(dest_prefix, &ssts).await;
+        for sst in &ssts {
+            sst.unmark_compaction();
+        }
 
-            plan_for_all_segments.push(plan);
+        res
+    }
+
+    async fn delete_range(&self, range: TimeRange) -> Result<DeleteRangeResult> {
+        let closed = self.closed.read().await;
+        ensure!(!*closed, "storage is closed");
+
+        let ssts = self.manifest.find_ssts(&range).await;
+        let partial = ssts
+            .iter()
+            .filter(|f| {
+                let time_range = &f.meta().time_range;
+                time_range.start < range.start || range.end < time_range.end
+            })
+            .map(|f| (f.id(), f.meta().time_range.clone()))
+            .collect::<Vec<_>>();
+        ensure!(
+            partial.is_empty(),
+            "range partially covers ssts, range:{range:?}, ssts:{partial:?}"
+        );
+
+        let mut result = DeleteRangeResult::default();
+        let mut to_deletes = Vec::new();
+        for sst in ssts {
+            // Mark it so compaction won't pick it while deleting.
+            if sst.try_mark_compaction() {
+                to_deletes.push(sst);
+            } else {
+                result.skipped.push(sst.id());
+            }
+        }
+        if to_deletes.is_empty() {
+            return Ok(result);
         }
 
-        let ctx = SessionContext::default();
-        if plan_for_all_segments.len() == 1 {
-            let res = execute_stream(plan_for_all_segments.remove(0), ctx.task_ctx())
-                .context("execute stream")?;
-            return Ok(res);
+        result.deleted = to_deletes.iter().map(|f| f.id()).collect();
+        if let Err(e) = self
+            .manifest
+            .update(ManifestUpdate::new(vec![], result.deleted.clone()))
+            .await
+        {
+            for sst in &to_deletes {
+                sst.unmark_compaction();
+            }
+            return Err(e);
         }
 
-        let union_exec = Arc::new(UnionExec::new(plan_for_all_segments));
-        let res = execute_stream(union_exec, ctx.task_ctx()).context("execute stream")?;
-        return Ok(res);
+        // Manifest is updated, failing to delete files only leaves garbage.
+        let deletes = result.deleted.iter().map(|id| async move {
+            let path = Path::from(self.sst_path_gen.generate(*id));
+            if let Err(e) = self.store.delete(&path).await {
+                error!(id, "Failed to delete sst, err:{e}");
+            }
+        });
+        join_all(deletes).await;
+        info!(?range, deleted = ?result.deleted, "Delete range finished");
+
+        Ok(result)
     }
 
-    async fn compact(&self, _req: CompactRequest) -> Result<()> {
-        self.compact_scheduler.trigger_compaction()
+    fn pause_compaction(&self) {
+        self.compact_scheduler.pause();
+    }
+
+    fn resume_compaction(&self) {
+        self.compact_scheduler.resume();
+    }
+
+    fn is_compaction_paused(&self) -> bool {
+        self.compact_scheduler.is_paused()
+    }
+
+    fn compaction_tasks(&self) -> Vec<CompactionTaskStatus> {
+        self.compact_scheduler.tasks()
+    }
+
+    async fn merge_manifest(&self) -> Result<()> {
+        self.manifest.merge_deltas().await
+    }
+
+    async fn dump_manifest(&self) -> Result<ManifestDump> {
+        self.manifest.dump().await
+    }
+
+    async fn list_ssts(&self) -> Vec<SstStatus> {
+        self.manifest.list_ssts().await
+    }
+
+    async fn manifest_summary(&self) -> ManifestSummary {
+        self.manifest.summary().await
+    }
+
+    fn register_manifest_observer(&self, observer: ManifestObserverRef) {
+        self.manifest.register_observer(observer);
+    }
+
+    async fn close(&self) -> Result<()> {
+        let mut closed = self.closed.write().await;
+        if *closed {
+            return Ok(());
+        }
+        *closed = true;
+
+        self.compact_scheduler.close().await;
+        self.manifest.merge_deltas().await?;
+        info!(path = self.path, "Storage closed");
+
+        Ok(())
+    }
+}
+
+/// Copy a snapshot exported by [`TimeMergeStorage::export_snapshot`] to
+/// `root_dir`, then a storage could be opened there. Returns ssts restored.
+///
+/// `root_dir` should not contain a storage already.
+pub async fn restore_snapshot(
+    store: &ObjectStoreRef,
+    snapshot_prefix: &str,
+    root_dir: &str,
+) -> Result<Vec<FileId>> {
+    let existing = dump_manifest(store, root_dir).await?;
+    ensure!(
+        existing.snapshot.is_empty() && existing.deltas.is_empty(),
+        "storage already exists in {root_dir}"
+    );
+    let snapshot = dump_manifest(store, snapshot_prefix).await?;
+    ensure!(
+        snapshot.deltas.is_empty(),
+        "snapshot {snapshot_prefix} is not exported, it has pending deltas"
+    );
+
+    let src_path_gen = SstPathGenerator::new(snapshot_prefix.to_string());
+    let dest_path_gen = SstPathGenerator::new(root_dir.to_string());
+    let mut ssts = Vec::with_capacity(snapshot.snapshot.len());
+    for sst in snapshot.snapshot {
+        let from = Path::from(src_path_gen.generate(sst.id));
+        let to = Path::from(dest_path_gen.generate(sst.id));
+        store
+            .copy(&from, &to)
+            .await
+            .with_context(|| format!("copy sst from {from} to {to}"))?;
+        let file_meta = FileMeta {
+            max_sequence: sst.max_sequence,
+            num_rows: sst.num_rows,
+            size: sst.size,
+            time_range: (sst.time_range.0..sst.time_range.1).into(),
+        };
+        ssts.push(SstFile::new(sst.id, file_meta));
     }
+    let ids = ssts.iter().map(|f| f.id()).collect();
+    // Write snapshot last, so a half restored dir won't be seen as a storage.
+    write_snapshot(store, root_dir, ssts).await?;
+    info!(snapshot_prefix, root_dir, "Restore snapshot finished");
+
+    Ok(ids)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
+    use arrow::{
+        array::AsArray,
+        datatypes::{UInt32Type, UInt64Type, UInt8Type},
+    };
     use datafusion::logical_expr::{col, lit};
     use object_store::local::LocalFileSystem;
+    use parquet::basic::{Compression, ZstdLevel};
     use test_log::test;
 
     use super::*;
-    use crate::{arrow_schema, record_batch, test_util::check_stream, types::Timestamp};
+    use crate::{
+        arrow_schema,
+        config::{ColumnOptions, ParquetCompression, QuotaConfig, SchedulerConfig, UpdateMode},
+        record_batch,
+        test_util::check_stream,
+        types::Timestamp,
+    };
 
     fn build_runtimes() -> StorageRuntimes {
         let rt = Arc::new(Runtime::new().unwrap());
@@ -441,6 +1216,8 @@ mod tests {
                     range: TimeRange::new(Timestamp(0), Timestamp::MAX),
                     predicate: vec![],
                     projections: None,
+                    timeout: Some(Duration::from_secs(60)),
+                    limit: None,
                 })
                 .await
                 .unwrap();
@@ -468,6 +1245,8 @@ mod tests {
                     range: TimeRange::new(Timestamp(0), Timestamp::MAX),
                     predicate: vec![expr],
                     projections: None,
+                    timeout: None,
+                    limit: None,
                 })
                 .await
                 .unwrap();
@@ -486,29 +1265,885 @@ mod tests {
                 .unwrap(),
             ];
             check_stream(result_stream, expected_batch).await;
+
+            // test with limit
+            let result_stream = storage
+                .scan(ScanRequest {
+                    range: TimeRange::new(Timestamp(0), Timestamp::MAX),
+                    predicate: vec![],
+                    projections: None,
+                    timeout: None,
+                    limit: Some(2),
+                })
+                .await
+                .unwrap();
+            let expected_batch = [record_batch!(
+                ("pk1", UInt8, vec![5, 9]),
+                ("pk2", UInt8, vec![3, 1]),
+                ("value", Int64, vec![1, 44])
+            )
+            .unwrap()];
+            check_stream(result_stream, expected_batch).await;
         });
     }
 
-    #[test]
-    fn test_storage_sort_batch() {
-        let schema = arrow_schema!(("a", UInt8), ("b", UInt8), ("c", UInt8), ("c", UInt8));
+    #[test(test)]
+    fn test_storage_write_and_scan_with_dedup_keys() {
+        let schema = arrow_schema!(("pk1", UInt8), ("pk2", UInt8), ("value", Int64));
         let root_dir = temp_dir::TempDir::new().unwrap();
         let store = Arc::new(LocalFileSystem::new());
         let runtimes = build_runtimes();
         runtimes.sst_compact_runtime.clone().block_on(async move {
+            let config = StorageConfig {
+                num_dedup_keys: Some(1),
+                ..Default::default()
+            };
             let storage = CloudObjectStorage::try_new(
                 root_dir.path().to_string_lossy().to_string(),
                 Duration::from_hours(2),
                 store,
                 schema.clone(),
-                1,
-                StorageConfig::default(),
+                2, // num_primary_keys
+                config,
                 runtimes,
             )
             .await
             .unwrap();
-            let batch = record_batch!(
-                ("a", UInt8, vec![2, 1, 3, 4, 8, 6, 5, 7]),
+
+            for (batch, time_range) in [
+                (
+                    record_batch!(
+                        ("pk1", UInt8, vec![9, 10, 11]),
+                        ("pk2", UInt8, vec![1, 2, 3]),
+                        ("value", Int64, vec![1, 2, 3])
+                    )
+                    .unwrap(),
+                    (1..10).into(),
+                ),
+                (
+                    record_batch!(
+                        ("pk1", UInt8, vec![10, 11]),
+                        ("pk2", UInt8, vec![5, 0]),
+                        ("value", Int64, vec![20, 30])
+                    )
+                    .unwrap(),
+                    (10..20).into(),
+                ),
+            ] {
+                storage
+                    .write(WriteRequest {
+                        batch,
+                        time_range,
+                        enable_check: true,
+                    })
+                    .await
+                    .unwrap();
+            }
+
+            // pk2 is ignored when dedup, so the latest written row wins.
+            let result_stream = storage
+                .scan(ScanRequest {
+                    range: TimeRange::new(Timestamp(0), Timestamp::MAX),
+                    predicate: vec![],
+                    projections: None,
+                    timeout: None,
+                    limit: None,
+                })
+                .await
+                .unwrap();
+            let expected_batch = [
+                record_batch!(
+                    ("pk1", UInt8, vec![9, 10]),
+                    ("pk2", UInt8, vec![1, 5]),
+                    ("value", Int64, vec![1, 20])
+                )
+                .unwrap(),
+                record_batch!(
+                    ("pk1", UInt8, vec![11]),
+                    ("pk2", UInt8, vec![0]),
+                    ("value", Int64, vec![30])
+                )
+                .unwrap(),
+            ];
+            check_stream(result_stream, expected_batch).await;
+        });
+    }
+
+    #[test]
+    fn test_storage_close() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store,
+                schema.clone(),
+                1, // num_primary_keys
+                StorageConfig::default(),
+                runtimes,
+            )
+            .await
+            .unwrap();
+
+            let write_req = || WriteRequest {
+                batch: record_batch!(("pk1", UInt8, vec![1, 2]), ("value", Int64, vec![3, 4]))
+                    .unwrap(),
+                time_range: (1..10).into(),
+                enable_check: true,
+            };
+            storage.write(write_req()).await.unwrap();
+
+            storage.close().await.unwrap();
+            let dump = storage.dump_manifest().await.unwrap();
+            assert_eq!(dump.snapshot.len(), 1);
+            assert!(dump.deltas.is_empty());
+
+            let err = storage.write(write_req()).await.unwrap_err();
+            assert!(err.to_string().contains("closed"), "{err}");
+            // Close again is fine.
+            storage.close().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_storage_merge_manifest_in_current_thread() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let root_dir = root_dir.path().to_string_lossy().to_string();
+        let store: ObjectStoreRef = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        // Same as actix workers.
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async move {
+            let open = || {
+                CloudObjectStorage::try_new(
+                    root_dir.clone(),
+                    Duration::from_hours(2),
+                    store.clone(),
+                    schema.clone(),
+                    1, // num_primary_keys
+                    StorageConfig::default(),
+                    runtimes.clone(),
+                )
+            };
+            let write_req = || WriteRequest {
+                batch: record_batch!(("pk1", UInt8, vec![1, 2]), ("value", Int64, vec![3, 4]))
+                    .unwrap(),
+                time_range: (1..10).into(),
+                enable_check: true,
+            };
+
+            let storage = open().await.unwrap();
+            storage.write(write_req()).await.unwrap();
+            storage.merge_manifest().await.unwrap();
+            let dump = storage.dump_manifest().await.unwrap();
+            assert_eq!(dump.snapshot.len(), 1);
+            assert!(dump.deltas.is_empty());
+
+            // Deltas are merged when open too.
+            storage.write(write_req()).await.unwrap();
+            let storage = open().await.unwrap();
+            let dump = storage.dump_manifest().await.unwrap();
+            assert_eq!(dump.snapshot.len(), 2);
+            assert!(dump.deltas.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_storage_pause_compaction() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let config = StorageConfig {
+                scheduler: SchedulerConfig {
+                    input_sst_min_num: 2,
+                    paused: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store,
+                schema.clone(),
+                1, // num_primary_keys
+                config,
+                runtimes,
+            )
+            .await
+            .unwrap();
+            // Paused since open.
+            assert!(storage.is_compaction_paused());
+            storage.pause_compaction();
+            assert!(storage.is_compaction_paused());
+            for pk in 1..=3 {
+                let batch =
+                    record_batch!(("pk1", UInt8, vec![pk]), ("value", Int64, vec![1])).unwrap();
+                storage
+                    .write(WriteRequest {
+                        batch,
+                        time_range: (pk as i64..10).into(),
+                        enable_check: true,
+                    })
+                    .await
+                    .unwrap();
+            }
+
+            // Triggered compaction is skipped while paused.
+            storage.compact(CompactRequest::default()).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            assert!(storage.compaction_tasks().is_empty());
+            assert_eq!(storage.list_ssts().await.len(), 3);
+
+            // Resume triggers a pick immediately.
+            storage.resume_compaction();
+            assert!(!storage.is_compaction_paused());
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while storage.list_ssts().await.len() != 1 {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                }
+            })
+            .await
+            .unwrap();
+            let ssts = storage.list_ssts().await;
+            assert_eq!(ssts[0].sst.num_rows, 3);
+        });
+    }
+
+    #[test]
+    fn test_storage_quota() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let scan_quota = ReadableSize(1000);
+            let config = StorageConfig {
+                quota: QuotaConfig {
+                    write_rows_per_sec: Some(3),
+                    scan_bytes_per_sec: Some(scan_quota),
+                },
+                ..Default::default()
+            };
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store,
+                schema.clone(),
+                1, // num_primary_keys
+                config,
+                runtimes,
+            )
+            .await
+            .unwrap();
+            let write = |pks: Vec<u8>| {
+                let values = vec![1; pks.len()];
+                let batch = record_batch!(("pk1", UInt8, pks), ("value", Int64, values)).unwrap();
+                storage.write(WriteRequest {
+                    batch,
+                    time_range: (0..10).into(),
+                    enable_check: true,
+                })
+            };
+            write(vec![1, 2]).await.unwrap();
+            let err = write(vec![3, 4]).await.unwrap_err();
+            assert!(matches!(err, Error::QuotaExceeded(_)), "{err}");
+            write(vec![3]).await.unwrap();
+
+            let scan = || {
+                storage.scan(ScanRequest {
+                    range: TimeRange::new(Timestamp(0), Timestamp::MAX),
+                    predicate: vec![],
+                    projections: None,
+                    timeout: None,
+                    limit: None,
+                })
+            };
+            let consume = |stream: SendableRecordBatchStream| async move {
+                stream.try_collect::<Vec<_>>().await.unwrap();
+            };
+            // Ssts are larger than the quota, but only bytes read are charged, which
+            // exclude the footer.
+            let sst_size = storage
+                .list_ssts()
+                .await
+                .iter()
+                .map(|s| s.sst.size as u64)
+                .sum::<u64>();
+            assert!(sst_size > scan_quota.as_byte(), "{sst_size}");
+            for _ in 0..2 {
+                consume(scan().await.unwrap()).await;
+            }
+            // Bytes are charged while consuming the stream, so it's used up after
+            // some scans.
+            let mut scan_err = None;
+            for _ in 0..10 {
+                match scan().await {
+                    Ok(stream) => consume(stream).await,
+                    Err(e) => {
+                        scan_err = Some(e);
+                        break;
+                    }
+                }
+            }
+            let err = scan_err.expect("scan should exceed quota");
+            assert!(matches!(err, Error::QuotaExceeded(_)), "{err}");
+        });
+    }
+
+    #[test]
+    fn test_storage_delete_range() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store,
+                schema.clone(),
+                1, // num_primary_keys
+                StorageConfig::default(),
+                runtimes,
+            )
+            .await
+            .unwrap();
+            for time_range in [1..10, 10..20, 25..30] {
+                let batch =
+                    record_batch!(("pk1", UInt8, vec![1]), ("value", Int64, vec![1])).unwrap();
+                storage
+                    .write(WriteRequest {
+                        batch,
+                        time_range: time_range.into(),
+                        enable_check: true,
+                    })
+                    .await
+                    .unwrap();
+            }
+            let ssts = storage.list_ssts().await;
+            let ids = ssts.iter().map(|s| s.sst.id).collect::<Vec<_>>();
+
+            // Nothing is removed when any sst is partially covered.
+            let err = storage.delete_range((5..20).into()).await.unwrap_err();
+            assert!(err.to_string().contains("partially covers"), "{err}");
+            assert_eq!(storage.list_ssts().await.len(), 3);
+
+            let result = storage.delete_range((0..20).into()).await.unwrap();
+            assert_eq!(
+                result,
+                DeleteRangeResult {
+                    deleted: ids[..2].to_vec(),
+                    skipped: vec![],
+                }
+            );
+            let ssts = storage.list_ssts().await;
+            assert_eq!(ssts.len(), 1);
+            assert_eq!(ssts[0].sst.id, ids[2]);
+            assert!(!ssts[0].being_compacted);
+            for id in &ids[..2] {
+                let path = Path::from(storage.sst_path_gen.generate(*id));
+                assert!(storage.store.head(&path).await.is_err());
+            }
+
+            let result = storage.delete_range((0..5).into()).await.unwrap();
+            assert_eq!(result, DeleteRangeResult::default());
+        });
+    }
+
+    #[test]
+    fn test_storage_delete_range_with_compaction() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
+        let runtimes = build_runtimes();
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            for _ in 0..10 {
+                let root_dir = temp_dir::TempDir::new().unwrap();
+                let config = StorageConfig {
+                    scheduler: SchedulerConfig {
+                        input_sst_min_num: 2,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+                let storage = CloudObjectStorage::try_new(
+                    root_dir.path().to_string_lossy().to_string(),
+                    Duration::from_hours(2),
+                    Arc::new(LocalFileSystem::new()),
+                    schema.clone(),
+                    1, // num_primary_keys
+                    config,
+                    runtimes.clone(),
+                )
+                .await
+                .unwrap();
+                for pk in 1..=4 {
+                    let batch =
+                        record_batch!(("pk1", UInt8, vec![pk]), ("value", Int64, vec![1])).unwrap();
+                    storage
+                        .write(WriteRequest {
+                            batch,
+                            time_range: (pk as i64..10).into(),
+                            enable_check: true,
+                        })
+                        .await
+                        .unwrap();
+                }
+                let ids = storage
+                    .list_ssts()
+                    .await
+                    .iter()
+                    .map(|s| s.sst.id)
+                    .collect::<Vec<_>>();
+
+                // Both of them try to take all ssts.
+                let (compact_res, delete_res) = tokio::join!(
+                    storage.compact(CompactRequest::default()),
+                    storage.delete_range((0..10).into())
+                );
+                compact_res.unwrap();
+                let result = delete_res.unwrap();
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                storage.compact_scheduler.wait_idle().await;
+
+                // Every sst in manifest must still exist.
+                let ssts = storage.list_ssts().await;
+                for sst in &ssts {
+                    assert!(!sst.being_compacted);
+                    let path = Path::from(storage.sst_path_gen.generate(sst.sst.id));
+                    storage.store.head(&path).await.unwrap();
+                }
+                // Rows of deleted ssts must not be brought back by compaction.
+                let stream = storage
+                    .scan(ScanRequest {
+                        range: TimeRange::new(Timestamp(0), Timestamp::MAX),
+                        predicate: vec![],
+                        projections: None,
+                        timeout: None,
+                        limit: None,
+                    })
+                    .await
+                    .unwrap();
+                let batches = stream.try_collect::<Vec<_>>().await.unwrap();
+                let pks = batches
+                    .iter()
+                    .flat_map(|b| b.column(0).as_primitive::<UInt8Type>().values().to_vec())
+                    .collect::<Vec<_>>();
+                for (id, pk) in ids.iter().zip(1..=4_u8) {
+                    assert_eq!(result.deleted.contains(id), !pks.contains(&pk));
+                }
+
+                storage.close().await.unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_storage_ingest() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        let write_parquet = |name: &str, batch: RecordBatch| {
+            let path = root_dir.path().join(name);
+            let file = File::create(&path).unwrap();
+            let mut writer =
+                parquet::arrow::ArrowWriter::try_new(file, batch.schema(), None).unwrap();
+            writer.write(&batch).unwrap();
+            writer.close().unwrap();
+            path.to_string_lossy().to_string()
+        };
+        let good_file = write_parquet(
+            "good.parquet",
+            record_batch!(
+                ("pk1", UInt8, vec![3, 1, 2]),
+                ("value", Int64, vec![30, 10, 20])
+            )
+            .unwrap(),
+        );
+        let bad_file = write_parquet(
+            "bad.parquet",
+            record_batch!(("pk1", UInt8, vec![1]), ("value", Int32, vec![1])).unwrap(),
+        );
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().join("db").to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store,
+                schema.clone(),
+                1, // num_primary_keys
+                StorageConfig::default(),
+                runtimes,
+            )
+            .await
+            .unwrap();
+
+            let ingest_file = |path: &str| IngestFile {
+                path: path.to_string(),
+                time_range: (0..10).into(),
+                time_column: None,
+            };
+            let err = storage
+                .ingest(IngestRequest {
+                    files: vec![ingest_file(&good_file), ingest_file(&bad_file)],
+                })
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("mismatch"), "{err}");
+            assert!(storage.list_ssts().await.is_empty());
+
+            let ids = storage
+                .ingest(IngestRequest {
+                    files: vec![ingest_file(&good_file)],
+                })
+                .await
+                .unwrap();
+            assert_eq!(ids.len(), 1);
+            let result_stream = storage
+                .scan(ScanRequest {
+                    range: TimeRange::new(Timestamp(0), Timestamp::MAX),
+                    predicate: vec![],
+                    projections: None,
+                    timeout: None,
+                    limit: None,
+                })
+                .await
+                .unwrap();
+            let expected_batch = [
+                record_batch!(("pk1", UInt8, vec![1, 2]), ("value", Int64, vec![10, 20])).unwrap(),
+                record_batch!(("pk1", UInt8, vec![3]), ("value", Int64, vec![30])).unwrap(),
+            ];
+            check_stream(result_stream, expected_batch).await;
+        });
+    }
+
+    #[test]
+    fn test_storage_ingest_with_time_column() {
+        let schema = arrow_schema!(("pk1", UInt32), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        // Rows are in reverse order of pk, and split into many batches when read.
+        let num_rows = 300_000;
+        let file = {
+            let pks = (0..num_rows).rev().collect::<Vec<u32>>();
+            let times = pks.iter().map(|v| *v as i64).collect::<Vec<_>>();
+            let values = pks.iter().map(|v| *v as i64 * 10).collect::<Vec<_>>();
+            let batch = record_batch!(
+                ("pk1", UInt32, pks),
+                ("ts", Int64, times),
+                ("value", Int64, values)
+            )
+            .unwrap();
+            let path = root_dir.path().join("data.parquet");
+            let props = WriterProperties::builder()
+                .set_max_row_group_size(10_000)
+                .build();
+            let mut writer = parquet::arrow::ArrowWriter::try_new(
+                File::create(&path).unwrap(),
+                batch.schema(),
+                Some(props),
+            )
+            .unwrap();
+            writer.write(&batch).unwrap();
+            writer.close().unwrap();
+            path.to_string_lossy().to_string()
+        };
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let config = StorageConfig {
+                write: WriteConfig {
+                    // The file is larger than it, so it's spilled in runs.
+                    sort_memory_limit: Some(ReadableSize::mb(4)),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().join("db").to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store,
+                schema.clone(),
+                1, // num_primary_keys
+                config,
+                runtimes,
+            )
+            .await
+            .unwrap();
+
+            let ingest_file = |time_range: std::ops::Range<i64>| IngestFile {
+                path: file.clone(),
+                time_range: time_range.into(),
+                time_column: Some("ts".to_string()),
+            };
+            for time_range in [0..10, 1..300_000, 0..299_999] {
+                let err = storage
+                    .ingest(IngestRequest {
+                        files: vec![ingest_file(time_range)],
+                    })
+                    .await
+                    .unwrap_err();
+                assert!(err.to_string().contains("out of time range"), "{err}");
+            }
+            assert!(storage.list_ssts().await.is_empty());
+
+            let ids = storage
+                .ingest(IngestRequest {
+                    files: vec![ingest_file(0..300_000)],
+                })
+                .await
+                .unwrap();
+            assert_eq!(ids.len(), 1);
+            let ssts = storage.list_ssts().await;
+            assert_eq!(ssts[0].sst.num_rows, num_rows);
+            assert_eq!(ssts[0].sst.time_range, (0, 300_000));
+
+            let batches = storage
+                .scan(ScanRequest {
+                    range: TimeRange::new(Timestamp(0), Timestamp::MAX),
+                    predicate: vec![],
+                    projections: None,
+                    timeout: None,
+                    limit: None,
+                })
+                .await
+                .unwrap()
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap();
+            let pks = batches
+                .iter()
+                .flat_map(|b| b.column(0).as_primitive::<UInt32Type>().values().to_vec())
+                .collect::<Vec<_>>();
+            assert_eq!(pks, (0..num_rows).collect::<Vec<_>>());
+            let values = batches
+                .iter()
+                .flat_map(|b| b.column(1).as_primitive::<Int64Type>().values().to_vec())
+                .collect::<Vec<_>>();
+            assert_eq!(
+                values,
+                (0..num_rows as i64).map(|v| v * 10).collect::<Vec<_>>()
+            );
+        });
+    }
+
+    #[test]
+    fn test_storage_export_and_restore_snapshot() {
+        let schema = arrow_schema!(("pk1", UInt8), ("value", Int64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store: ObjectStoreRef = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let open = |dir: &str| {
+                CloudObjectStorage::try_new(
+                    root_dir.path().join(dir).to_string_lossy().to_string(),
+                    Duration::from_hours(2),
+                    store.clone(),
+                    schema.clone(),
+                    1, // num_primary_keys
+                    StorageConfig::default(),
+                    runtimes.clone(),
+                )
+            };
+            let storage = open("db").await.unwrap();
+            for (pk, time_range) in [(1, 1..10), (2, 10..20)] {
+                let batch =
+                    record_batch!(("pk1", UInt8, vec![pk]), ("value", Int64, vec![pk as i64]))
+                        .unwrap();
+                storage
+                    .write(WriteRequest {
+                        batch,
+                        time_range: time_range.into(),
+                        enable_check: true,
+                    })
+                    .await
+                    .unwrap();
+            }
+
+            let dest = root_dir.path().join("backup").to_string_lossy().to_string();
+            let result = storage.export_snapshot(&dest).await.unwrap();
+            assert_eq!(result.ssts.len(), 2);
+            assert!(!storage.is_compaction_paused());
+
+            // Concurrent exports run one by one, both copy all ssts.
+            let ssts = storage.list_ssts().await;
+            let ids = ssts.iter().map(|sst| sst.sst.id).collect::<Vec<_>>();
+            let dests = ["backup3", "backup4"]
+                .map(|dir| root_dir.path().join(dir).to_string_lossy().to_string());
+            let (res1, res2) = tokio::join!(
+                storage.export_snapshot(&dests[0]),
+                storage.export_snapshot(&dests[1])
+            );
+            assert_eq!(res1.unwrap().ssts, ids);
+            assert_eq!(res2.unwrap().ssts, ids);
+            for dir in ["backup3", "backup4"] {
+                let exported = open(dir).await.unwrap();
+                assert_eq!(exported.list_ssts().await, ssts);
+            }
+            assert!(storage
+                .list_ssts()
+                .await
+                .iter()
+                .all(|sst| !sst.being_compacted));
+
+            let exported = open("backup").await.unwrap();
+            assert_eq!(exported.list_ssts().await, storage.list_ssts().await);
+            let result_stream = exported
+                .scan(ScanRequest {
+                    range: TimeRange::new(Timestamp(0), Timestamp::MAX),
+                    predicate: vec![],
+                    projections: None,
+                    timeout: None,
+                    limit: None,
+                })
+                .await
+                .unwrap();
+            let expected_batch = [
+                record_batch!(("pk1", UInt8, vec![1]), ("value", Int64, vec![1])).unwrap(),
+                record_batch!(("pk1", UInt8, vec![2]), ("value", Int64, vec![2])).unwrap(),
+            ];
+            check_stream(result_stream, expected_batch).await;
+            drop(exported);
+
+            let restored_dir = root_dir
+                .path()
+                .join("restored")
+                .to_string_lossy()
+                .to_string();
+            let ids = restore_snapshot(&store, &dest, &restored_dir)
+                .await
+                .unwrap();
+            assert_eq!(ids, result.ssts);
+            assert!(restore_snapshot(&store, &dest, &restored_dir)
+                .await
+                .is_err());
+            let restored = open("restored").await.unwrap();
+            assert_eq!(restored.list_ssts().await, storage.list_ssts().await);
+
+            // Export pauses are nested and not affected by resume.
+            let guard1 = storage.compact_scheduler.pause_until_idle().await;
+            let guard2 = storage.compact_scheduler.pause_until_idle().await;
+            storage.resume_compaction();
+            assert!(storage.is_compaction_paused());
+            drop(guard1);
+            assert!(storage.is_compaction_paused());
+            drop(guard2);
+            assert!(!storage.is_compaction_paused());
+
+            // Ssts being exported won't be deleted, and vice versa.
+            let dest = root_dir
+                .path()
+                .join("backup2")
+                .to_string_lossy()
+                .to_string();
+            let (export_res, delete_res) = tokio::join!(
+                storage.export_snapshot(&dest),
+                storage.delete_range((0..20).into())
+            );
+            let deleted = delete_res.unwrap().deleted;
+            // Export either copies all ssts before they are deleted, or fails.
+            match export_res {
+                Ok(result) => {
+                    assert_eq!(result.ssts.len(), 2);
+                    let dest_path_gen = SstPathGenerator::new(dest);
+                    for id in result.ssts {
+                        let path = Path::from(dest_path_gen.generate(id));
+                        store.head(&path).await.unwrap();
+                    }
+                }
+                Err(e) => assert!(e.to_string().contains("retry export later"), "{e}"),
+            }
+            let ssts = storage.list_ssts().await;
+            assert_eq!(ssts.len() + deleted.len(), 2);
+            assert!(ssts.iter().all(|sst| !sst.being_compacted));
+        });
+    }
+
+    #[test]
+    fn test_build_write_props() {
+        let arrow_schema = arrow_schema!(("pk1", UInt8), ("pk2", UInt8), ("value", Int64));
+        let schema = StorageSchema::try_new(arrow_schema, 2, UpdateMode::Overwrite).unwrap();
+        let write_options = WriteConfig {
+            enable_primary_key_bloom_filter: true,
+            ..Default::default()
+        };
+        let props = CloudObjectStorage::build_write_props(write_options, &schema).unwrap();
+        for (col_name, expected) in [("pk1", true), ("pk2", true), ("value", false)] {
+            let col_path = ColumnPath::new(vec![col_name.to_string()]);
+            assert_eq!(
+                props.bloom_filter_properties(&col_path).is_some(),
+                expected,
+                "column:{col_name}"
+            );
+        }
+
+        let write_options = WriteConfig {
+            compression: ParquetCompression::Zstd,
+            compression_level: Some(3),
+            column_options: Some(HashMap::from([
+                (
+                    "pk1".to_string(),
+                    ColumnOptions {
+                        compression: Some(ParquetCompression::Lz4),
+                        ..Default::default()
+                    },
+                ),
+                (
+                    "value".to_string(),
+                    ColumnOptions {
+                        compression_level: Some(9),
+                        ..Default::default()
+                    },
+                ),
+            ])),
+            ..Default::default()
+        };
+        let props = CloudObjectStorage::build_write_props(write_options, &schema).unwrap();
+        for (col_name, expected) in [
+            ("pk1", Compression::LZ4_RAW),
+            ("pk2", Compression::ZSTD(ZstdLevel::try_new(3).unwrap())),
+            ("value", Compression::ZSTD(ZstdLevel::try_new(9).unwrap())),
+        ] {
+            let col_path = ColumnPath::new(vec![col_name.to_string()]);
+            assert_eq!(props.compression(&col_path), expected, "column:{col_name}");
+        }
+
+        // Level is not supported by snappy.
+        let write_options = WriteConfig {
+            compression: ParquetCompression::Snappy,
+            compression_level: Some(1),
+            ..Default::default()
+        };
+        assert!(CloudObjectStorage::build_write_props(write_options, &schema).is_err());
+    }
+
+    #[test]
+    fn test_storage_sort_batch() {
+        let schema = arrow_schema!(("a", UInt8), ("b", UInt8), ("c", UInt8), ("c", UInt8));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store,
+                schema.clone(),
+                1,
+                StorageConfig::default(),
+                runtimes,
+            )
+            .await
+            .unwrap();
+            let batch = record_batch!(
+                ("a", UInt8, vec![2, 1, 3, 4, 8, 6, 5, 7]),
                 ("b", UInt8, vec![1, 3, 4, 8, 2, 6, 5, 7]),
                 ("c", UInt8, vec![8, 6, 2, 4, 3, 1, 5, 7]),
                 ("d", UInt8, vec![2, 7, 4, 6, 1, 3, 5, 8])
@@ -533,4 +2168,55 @@ mod tests {
             }
         });
     }
+
+    #[test]
+    fn test_storage_sort_batch_with_spill() {
+        let schema = arrow_schema!(("a", UInt64), ("b", UInt64));
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let spill_dir = temp_dir::TempDir::new().unwrap();
+        let store = Arc::new(LocalFileSystem::new());
+        let runtimes = build_runtimes();
+        runtimes.sst_compact_runtime.clone().block_on(async move {
+            let config = StorageConfig {
+                write: WriteConfig {
+                    sort_memory_limit: Some(ReadableSize::mb(4)),
+                    sort_spill_dir: Some(spill_dir.path().to_string_lossy().to_string()),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let storage = CloudObjectStorage::try_new(
+                root_dir.path().to_string_lossy().to_string(),
+                Duration::from_hours(2),
+                store,
+                schema.clone(),
+                1,
+                config,
+                runtimes,
+            )
+            .await
+            .unwrap();
+            // 16MB in total, which exceeds the memory limit.
+            let num_rows = 1_000_000_u64;
+            let batch = record_batch!(
+                ("a", UInt64, (0..num_rows).rev().collect::<Vec<_>>()),
+                ("b", UInt64, (0..num_rows).collect::<Vec<_>>())
+            )
+            .unwrap();
+
+            let mut sorted_batches = storage.sort_batch(batch).await.unwrap();
+            let mut expected_a = 0;
+            while let Some(sorted_batch) = sorted_batches.next().await {
+                let sorted_batch = sorted_batch.unwrap();
+                let a = sorted_batch.column(0).as_primitive::<UInt64Type>();
+                let b = sorted_batch.column(1).as_primitive::<UInt64Type>();
+                for (a, b) in a.values().iter().zip(b.values()) {
+                    assert_eq!(*a, expected_a);
+                    assert_eq!(*b, num_rows - 1 - expected_a);
+                    expected_a += 1;
+                }
+            }
+            assert_eq!(expected_a, num_rows);
+        });
+    }
 }
@@ -0,0 +1,380 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! An object store wrapper which coalesces concurrent identical range reads.
+
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{
+    future::{BoxFuture, Shared, WeakShared},
+    stream::BoxStream,
+    FutureExt,
+};
+use object_store::{
+    path::Path, GetOptions, GetResult, ListResult, MultipartUpload, ObjectMeta, ObjectStore,
+    PutMultipartOpts, PutOptions, PutPayload, PutResult, Result,
+};
+
+use crate::types::ObjectStoreRef;
+
+const STORE_NAME: &str = "CoalescingStore";
+
+type FetchKey = (Path, Range<usize>);
+type FetchFuture = BoxFuture<'static, std::result::Result<Bytes, Arc<object_store::Error>>>;
+type SharedFetch = Shared<FetchFuture>;
+// Only weak refs are kept, so the fetch is dropped once all its waiters are
+// dropped, such as cancelled by timeout.
+type InflightFetches = Arc<Mutex<HashMap<FetchKey, (u64, WeakShared<FetchFuture>)>>>;
+
+/// Removes the in-flight entry when the fetch completes or is dropped.
+struct InflightGuard {
+    inflight: InflightFetches,
+    key: FetchKey,
+    id: u64,
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        let mut inflight = self.inflight.lock().unwrap();
+        // The entry may be replaced by a new fetch of the same key already.
+        if inflight
+            .get(&self.key)
+            .is_some_and(|(id, _)| *id == self.id)
+        {
+            inflight.remove(&self.key);
+        }
+    }
+}
+
+/// Counters of [`CoalescingStore`].
+#[derive(Debug, Default)]
+pub struct CoalescingStats {
+    /// Number of `get_range` requests received.
+    requests: AtomicU64,
+    /// Number of `get_range` requests served by another in-flight fetch.
+    coalesced: AtomicU64,
+}
+
+impl CoalescingStats {
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+}
+
+/// Deduplicates concurrent `get_range` requests with the same path and range,
+/// so that only one of them is sent to the underlying store, and the others
+/// wait for its result.
+///
+/// Other operations are forwarded to the underlying store directly.
+#[derive(Debug)]
+pub struct CoalescingStore {
+    inner: ObjectStoreRef,
+    inflight: InflightFetches,
+    next_fetch_id: AtomicU64,
+    stats: Arc<CoalescingStats>,
+}
+
+impl CoalescingStore {
+    pub fn new(inner: ObjectStoreRef) -> Self {
+        Self {
+            inner,
+            inflight: Arc::new(Mutex::new(HashMap::new())),
+            next_fetch_id: AtomicU64::new(0),
+            stats: Arc::new(CoalescingStats::default()),
+        }
+    }
+
+    pub fn stats(&self) -> Arc<CoalescingStats> {
+        self.stats.clone()
+    }
+
+    fn fetch(&self, key: FetchKey) -> SharedFetch {
+        let mut inflight = self.inflight.lock().unwrap();
+        if let Some(fetch) = inflight.get(&key).and_then(|(_, fetch)| fetch.upgrade()) {
+            self.stats.coalesced.fetch_add(1, Ordering::Relaxed);
+            return fetch;
+        }
+
+        let inner = self.inner.clone();
+        let id = self.next_fetch_id.fetch_add(1, Ordering::Relaxed);
+        let guard = InflightGuard {
+            inflight: self.inflight.clone(),
+            key: key.clone(),
+            id,
+        };
+        let (path, range) = key.clone();
+        let fetch = async move {
+            let _guard = guard;
+            // Later requests should issue a new fetch after the result is produced,
+            // the guard is dropped along with this future then.
+            inner.get_range(&path, range).await.map_err(Arc::new)
+        }
+        .boxed()
+        .shared();
+        // The fetch is not polled yet, so it can always be downgraded.
+        inflight.insert(key, (id, fetch.downgrade().unwrap()));
+
+        fetch
+    }
+}
+
+impl Display for CoalescingStore {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{STORE_NAME}({})", self.inner)
+    }
+}
+
+/// Convert the shared error back to an owned one, `NotFound` is kept since
+/// callers may depend on it.
+fn to_owned_error(err: Arc<object_store::Error>) -> object_store::Error {
+    match err.as_ref() {
+        object_store::Error::NotFound { path, .. } => object_store::Error::NotFound {
+            path: path.clone(),
+            source: Box::new(err.clone()),
+        },
+        _ => object_store::Error::Generic {
+            store: STORE_NAME,
+            source: Box::new(err),
+        },
+    }
+}
+
+#[async_trait]
+impl ObjectStore for CoalescingStore {
+    async fn put_opts(
+        &self,
+        location: &Path,
+        payload: PutPayload,
+        opts: PutOptions,
+    ) -> Result<PutResult> {
+        self.inner.put_opts(location, payload, opts).await
+    }
+
+    async fn put_multipart_opts(
+        &self,
+        location: &Path,
+        opts: PutMultipartOpts,
+    ) -> Result<Box<dyn MultipartUpload>> {
+        self.inner.put_multipart_opts(location, opts).await
+    }
+
+    async fn get_opts(&self, location: &Path, options: GetOptions) -> Result<GetResult> {
+        self.inner.get_opts(location, options).await
+    }
+
+    async fn get_range(&self, location: &Path, range: Range<usize>) -> Result<Bytes> {
+        self.stats.requests.fetch_add(1, Ordering::Relaxed);
+        self.fetch((location.clone(), range))
+            .await
+            .map_err(to_owned_error)
+    }
+
+    async fn head(&self, location: &Path) -> Result<ObjectMeta> {
+        self.inner.head(location).await
+    }
+
+    async fn delete(&self, location: &Path) -> Result<()> {
+        self.inner.delete(location).await
+    }
+
+    fn list(&self, prefix: Option<&Path>) -> BoxStream<'_, Result<ObjectMeta>> {
+        self.inner.list(prefix)
+    }
+
+    fn list_with_offset(
+        &self,
+        prefix: Option<&Path>,
+        offset: &Path,
+    ) -> BoxStream<'_, Result<ObjectMeta>> {
+        self.inner.list_with_offset(prefix, offset)
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> Result<ListResult> {
+        self.inner.list_with_delimiter(prefix).await
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        self.inner.copy(from, to).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.inner.rename(from, to).await
+    }
+
+    async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> Result<()> {
+        self.inner.copy_if_not_exists(from, to).await
+    }
+
+    async fn rename_if_not_exists(&self, from: &Path, to: &Path) -> Result<()> {
+        self.inner.rename_if_not_exists(from, to).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use object_store::memory::InMemory;
+
+    use super::*;
+
+    /// A store whose reads are slow, and counts how many reads it served.
+    #[derive(Debug, Default)]
+    struct SlowStore {
+        inner: InMemory,
+        reads: AtomicU64,
+    }
+
+    impl Display for SlowStore {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "SlowStore")
+        }
+    }
+
+    #[async_trait]
+    impl ObjectStore for SlowStore {
+        async fn put_opts(
+            &self,
+            location: &Path,
+            payload: PutPayload,
+            opts: PutOptions,
+        ) -> Result<PutResult> {
+            self.inner.put_opts(location, payload, opts).await
+        }
+
+        async fn put_multipart_opts(
+            &self,
+            location: &Path,
+            opts: PutMultipartOpts,
+        ) -> Result<Box<dyn MultipartUpload>> {
+            self.inner.put_multipart_opts(location, opts).await
+        }
+
+        async fn get_opts(&self, location: &Path, options: GetOptions) -> Result<GetResult> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            self.inner.get_opts(location, options).await
+        }
+
+        async fn delete(&self, location: &Path) -> Result<()> {
+            self.inner.delete(location).await
+        }
+
+        fn list(&self, prefix: Option<&Path>) -> BoxStream<'_, Result<ObjectMeta>> {
+            self.inner.list(prefix)
+        }
+
+        async fn list_with_delimiter(&self, prefix: Option<&Path>) -> Result<ListResult> {
+            self.inner.list_with_delimiter(prefix).await
+        }
+
+        async fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+            self.inner.copy(from, to).await
+        }
+
+        async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> Result<()> {
+            self.inner.copy_if_not_exists(from, to).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_get_range() {
+        let slow_store = Arc::new(SlowStore::default());
+        let path = Path::from("data/1.sst");
+        slow_store
+            .put(&path, PutPayload::from_static(b"0123456789"))
+            .await
+            .unwrap();
+        let store = CoalescingStore::new(slow_store.clone());
+
+        let reads = (0..8).map(|_| store.get_range(&path, 2..6));
+        let results = futures::future::join_all(reads).await;
+        for res in results {
+            assert_eq!(res.unwrap(), Bytes::from_static(b"2345"));
+        }
+        assert_eq!(slow_store.reads.load(Ordering::Relaxed), 1);
+        assert_eq!(store.stats().requests(), 8);
+        assert_eq!(store.stats().coalesced(), 7);
+
+        // Different range is not coalesced.
+        let (a, b) = tokio::join!(store.get_range(&path, 0..2), store.get_range(&path, 6..8));
+        assert_eq!(a.unwrap(), Bytes::from_static(b"01"));
+        assert_eq!(b.unwrap(), Bytes::from_static(b"67"));
+        assert_eq!(slow_store.reads.load(Ordering::Relaxed), 3);
+
+        // Finished fetch is not reused.
+        store.get_range(&path, 2..6).await.unwrap();
+        assert_eq!(slow_store.reads.load(Ordering::Relaxed), 4);
+        assert_eq!(store.stats().coalesced(), 7);
+
+        let err = store
+            .get_range(&Path::from("data/2.sst"), 0..1)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, object_store::Error::NotFound { .. }));
+        assert!(store.inflight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_get_range_cancelled() {
+        let slow_store = Arc::new(SlowStore::default());
+        let path = Path::from("data/1.sst");
+        slow_store
+            .put(&path, PutPayload::from_static(b"0123456789"))
+            .await
+            .unwrap();
+        let store = CoalescingStore::new(slow_store.clone());
+        let timeout = Duration::from_millis(10);
+
+        // All waiters are cancelled, the entry should be removed.
+        let reads = (0..2).map(|_| tokio::time::timeout(timeout, store.get_range(&path, 2..6)));
+        for res in futures::future::join_all(reads).await {
+            assert!(res.is_err());
+        }
+        assert_eq!(store.stats().coalesced(), 1);
+        assert!(store.inflight.lock().unwrap().is_empty());
+
+        // Later request issues a new fetch.
+        let bytes = store.get_range(&path, 2..6).await.unwrap();
+        assert_eq!(bytes, Bytes::from_static(b"2345"));
+        assert_eq!(slow_store.reads.load(Ordering::Relaxed), 2);
+
+        // Cancelling one waiter doesn't affect the others.
+        let (cancelled, finished) = tokio::join!(
+            tokio::time::timeout(timeout, store.get_range(&path, 2..6)),
+            store.get_range(&path, 2..6)
+        );
+        assert!(cancelled.is_err());
+        assert_eq!(finished.unwrap(), Bytes::from_static(b"2345"));
+        assert_eq!(slow_store.reads.load(Ordering::Relaxed), 3);
+        assert!(store.inflight.lock().unwrap().is_empty());
+    }
+}
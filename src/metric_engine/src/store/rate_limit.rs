@@ -0,0 +1,359 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! An object store wrapper which limits the rate of requests.
+
+use std::{
+    fmt::{Display, Formatter},
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream::BoxStream, FutureExt, StreamExt};
+use object_store::{
+    path::Path, GetOptions, GetResult, ListResult, MultipartUpload, ObjectMeta, ObjectStore,
+    PutMultipartOpts, PutOptions, PutPayload, PutResult, Result, UploadPart,
+};
+
+use crate::{
+    config::{RateLimitConfig, TokenBucketConfig},
+    types::ObjectStoreRef,
+};
+
+/// A token bucket which allows bursts of up to one second worth of tokens.
+///
+/// Tokens are reserved before they are available, the caller waits for the
+/// returned duration, so that requests are served in order and a request
+/// larger than the bucket will not starve.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    // (available tokens, last refill time)
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: u64) -> Self {
+        let rate = rate as f64;
+        Self {
+            rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    /// Take `n` tokens, and returns how long the caller should wait before
+    /// they are available.
+    fn reserve(&self, n: u64) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.rate);
+        *last = now;
+        *tokens -= n as f64;
+        if *tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+
+    /// Take `n` tokens even if they are not available, `try_reserve` fails
+    /// until they are refilled.
+    pub(crate) fn charge(&self, n: u64) {
+        self.reserve(n);
+    }
+
+    /// Take `n` tokens only when they are available now, returns whether they
+    /// are taken.
+    ///
+    /// When the bucket is full, `n` larger than the bucket is taken as well,
+    /// otherwise it would never succeed.
+    pub(crate) fn try_reserve(&self, n: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.rate);
+        *last = now;
+        if *tokens >= n as f64 || *tokens >= self.rate {
+            *tokens -= n as f64;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RateLimiter {
+    ops: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    fn new(config: &TokenBucketConfig) -> Self {
+        Self {
+            ops: config.ops_per_sec.map(TokenBucket::new),
+            bytes: config.bytes_per_sec.map(|v| TokenBucket::new(v.as_byte())),
+        }
+    }
+
+    async fn acquire(&self, ops: u64, bytes: u64) {
+        let ops_wait = self
+            .ops
+            .as_ref()
+            .map(|b| b.reserve(ops))
+            .unwrap_or_default();
+        let bytes_wait = self
+            .bytes
+            .as_ref()
+            .filter(|_| bytes > 0)
+            .map(|b| b.reserve(bytes))
+            .unwrap_or_default();
+        let wait = ops_wait.max(bytes_wait);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Limits the ops/sec and bytes/sec of the underlying store, reads and writes
+/// are limited separately.
+///
+/// Bytes of a read are charged after the read when its size is unknown
+/// beforehand, the next reads will wait for them.
+#[derive(Debug)]
+pub struct StoreWithRateLimit {
+    inner: ObjectStoreRef,
+    read: Arc<RateLimiter>,
+    write: Arc<RateLimiter>,
+}
+
+impl StoreWithRateLimit {
+    pub fn new(inner: ObjectStoreRef, config: &RateLimitConfig) -> Self {
+        Self {
+            inner,
+            read: Arc::new(RateLimiter::new(&config.read)),
+            write: Arc::new(RateLimiter::new(&config.write)),
+        }
+    }
+
+    fn limit_list<'a>(
+        &self,
+        stream: BoxStream<'a, Result<ObjectMeta>>,
+    ) -> BoxStream<'a, Result<ObjectMeta>> {
+        let read = self.read.clone();
+        futures::stream::once(async move {
+            read.acquire(1, 0).await;
+            stream
+        })
+        .flatten()
+        .boxed()
+    }
+}
+
+impl Display for StoreWithRateLimit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "StoreWithRateLimit({})", self.inner)
+    }
+}
+
+#[async_trait]
+impl ObjectStore for StoreWithRateLimit {
+    async fn put_opts(
+        &self,
+        location: &Path,
+        payload: PutPayload,
+        opts: PutOptions,
+    ) -> Result<PutResult> {
+        self.write.acquire(1, payload.content_length() as u64).await;
+        self.inner.put_opts(location, payload, opts).await
+    }
+
+    async fn put_multipart_opts(
+        &self,
+        location: &Path,
+        opts: PutMultipartOpts,
+    ) -> Result<Box<dyn MultipartUpload>> {
+        self.write.acquire(1, 0).await;
+        let upload = self.inner.put_multipart_opts(location, opts).await?;
+        Ok(Box::new(MultipartUploadWithRateLimit {
+            inner: upload,
+            write: self.write.clone(),
+        }))
+    }
+
+    async fn get_opts(&self, location: &Path, options: GetOptions) -> Result<GetResult> {
+        self.read.acquire(1, 0).await;
+        let res = self.inner.get_opts(location, options).await?;
+        let size = (res.range.end - res.range.start) as u64;
+        self.read.acquire(0, size).await;
+        Ok(res)
+    }
+
+    async fn get_range(&self, location: &Path, range: Range<usize>) -> Result<Bytes> {
+        self.read.acquire(1, (range.end - range.start) as u64).await;
+        self.inner.get_range(location, range).await
+    }
+
+    async fn get_ranges(&self, location: &Path, ranges: &[Range<usize>]) -> Result<Vec<Bytes>> {
+        let size: usize = ranges.iter().map(|r| r.end - r.start).sum();
+        self.read.acquire(1, size as u64).await;
+        self.inner.get_ranges(location, ranges).await
+    }
+
+    async fn head(&self, location: &Path) -> Result<ObjectMeta> {
+        self.read.acquire(1, 0).await;
+        self.inner.head(location).await
+    }
+
+    async fn delete(&self, location: &Path) -> Result<()> {
+        self.write.acquire(1, 0).await;
+        self.inner.delete(location).await
+    }
+
+    fn list(&self, prefix: Option<&Path>) -> BoxStream<'_, Result<ObjectMeta>> {
+        self.limit_list(self.inner.list(prefix))
+    }
+
+    fn list_with_offset(
+        &self,
+        prefix: Option<&Path>,
+        offset: &Path,
+    ) -> BoxStream<'_, Result<ObjectMeta>> {
+        self.limit_list(self.inner.list_with_offset(prefix, offset))
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> Result<ListResult> {
+        self.read.acquire(1, 0).await;
+        self.inner.list_with_delimiter(prefix).await
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        self.write.acquire(1, 0).await;
+        self.inner.copy(from, to).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.write.acquire(1, 0).await;
+        self.inner.rename(from, to).await
+    }
+
+    async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> Result<()> {
+        self.write.acquire(1, 0).await;
+        self.inner.copy_if_not_exists(from, to).await
+    }
+
+    async fn rename_if_not_exists(&self, from: &Path, to: &Path) -> Result<()> {
+        self.write.acquire(1, 0).await;
+        self.inner.rename_if_not_exists(from, to).await
+    }
+}
+
+#[derive(Debug)]
+struct MultipartUploadWithRateLimit {
+    inner: Box<dyn MultipartUpload>,
+    write: Arc<RateLimiter>,
+}
+
+#[async_trait]
+impl MultipartUpload for MultipartUploadWithRateLimit {
+    fn put_part(&mut self, data: PutPayload) -> UploadPart {
+        let write = self.write.clone();
+        let size = data.content_length() as u64;
+        let part = self.inner.put_part(data);
+        async move {
+            write.acquire(1, size).await;
+            part.await
+        }
+        .boxed()
+    }
+
+    async fn complete(&mut self) -> Result<PutResult> {
+        self.write.acquire(1, 0).await;
+        self.inner.complete().await
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        self.inner.abort().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::ReadableSize;
+    use object_store::memory::InMemory;
+
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(10);
+        // Burst is allowed.
+        assert!(bucket.reserve(10).is_zero());
+        let wait = bucket.reserve(5);
+        assert!(
+            wait > Duration::from_millis(400) && wait <= Duration::from_millis(500),
+            "{wait:?}"
+        );
+        // Reserved tokens are accumulated.
+        let wait = bucket.reserve(5);
+        assert!(wait > Duration::from_millis(900), "{wait:?}");
+
+        let bucket = TokenBucket::new(10);
+        assert!(bucket.try_reserve(6));
+        assert!(!bucket.try_reserve(6));
+        assert!(bucket.try_reserve(4));
+        assert!(!bucket.try_reserve(1));
+        // Larger than the bucket, but it's full.
+        let bucket = TokenBucket::new(10);
+        assert!(bucket.try_reserve(20));
+        assert!(!bucket.try_reserve(1));
+    }
+
+    #[tokio::test]
+    async fn test_store_with_rate_limit() {
+        let config = RateLimitConfig {
+            write: TokenBucketConfig {
+                ops_per_sec: Some(1000),
+                bytes_per_sec: Some(ReadableSize(100)),
+            },
+            ..Default::default()
+        };
+        let store = StoreWithRateLimit::new(Arc::new(InMemory::new()), &config);
+        let path = Path::from("data/1.sst");
+        let begin = Instant::now();
+        for _ in 0..3 {
+            store
+                .put(&path, PutPayload::from_static(&[0; 50]))
+                .await
+                .unwrap();
+        }
+        // The first 100 bytes are burst, the last 50 bytes wait for 0.5s.
+        assert!(begin.elapsed() >= Duration::from_millis(450));
+
+        // Reads are not limited.
+        let begin = Instant::now();
+        for _ in 0..10 {
+            let bytes = store.get_range(&path, 0..50).await.unwrap();
+            assert_eq!(bytes.len(), 50);
+        }
+        assert!(begin.elapsed() < Duration::from_millis(450));
+    }
+}
@@ -150,6 +150,9 @@ pub struct WriteResult {
 pub struct StorageSchema {
     pub arrow_schema: SchemaRef,
     pub num_primary_keys: usize,
+    /// Rows with the same (0..num_dedup_keys) columns are merged, it's equal to
+    /// `num_primary_keys` unless set by `with_num_dedup_keys`.
+    pub num_dedup_keys: usize,
     pub seq_idx: usize,
     pub reserved_idx: usize,
     pub value_idxes: Vec<usize>,
@@ -188,6 +191,7 @@ impl StorageSchema {
         Ok(Self {
             arrow_schema,
             num_primary_keys,
+            num_dedup_keys: num_primary_keys,
             seq_idx,
             reserved_idx,
             value_idxes,
@@ -195,6 +199,18 @@ impl StorageSchema {
         })
     }
 
+    /// Dedup rows by a prefix of primary keys, so key columns with
+    /// non-deterministic values could be ignored when merging.
+    pub fn with_num_dedup_keys(mut self, num_dedup_keys: usize) -> Result<Self> {
+        ensure!(
+            num_dedup_keys > 0 && num_dedup_keys <= self.num_primary_keys,
+            "num_dedup_keys should be in [1, {}], current:{num_dedup_keys}",
+            self.num_primary_keys
+        );
+        self.num_dedup_keys = num_dedup_keys;
+        Ok(self)
+    }
+
     pub fn is_builtin_field(f: &FieldRef) -> bool {
         f.name() == SEQ_COLUMN_NAME || f.name() == RESERVED_COLUMN_NAME
     }
@@ -268,6 +284,18 @@ mod tests {
         assert_eq!(schema.seq_idx, 3);
         assert_eq!(schema.reserved_idx, 4);
 
+        assert_eq!(schema.num_dedup_keys, 2);
+        assert_eq!(
+            schema
+                .clone()
+                .with_num_dedup_keys(1)
+                .unwrap()
+                .num_dedup_keys,
+            1
+        );
+        assert!(schema.clone().with_num_dedup_keys(0).is_err());
+        assert!(schema.clone().with_num_dedup_keys(3).is_err());
+
         // No value column exists
         assert!(StorageSchema::try_new(arrow_schema, 3, UpdateMode::Append).is_err());
 
@@ -0,0 +1,52 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! Dump manifest of a storage as json, without starting the server.
+
+use std::sync::Arc;
+
+use clap::Parser;
+use metric_engine::{manifest::dump_manifest, types::ObjectStoreRef};
+use object_store::local::LocalFileSystem;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Dump manifest of a storage as json")]
+struct Args {
+    /// Root dir of the storage, such as `data_dir` in server config for the
+    /// bench table, or `{data_dir}/tables/{name}` for a named table.
+    root_dir: String,
+}
+
+fn main() {
+    let args = Args::parse();
+    let store: ObjectStoreRef = Arc::new(LocalFileSystem::new());
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("build tokio runtime");
+    let dump = match rt.block_on(dump_manifest(&store, &args.root_dir)) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("Dump manifest failed, err:{e}");
+            std::process::exit(1);
+        }
+    };
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&dump).expect("serialize manifest dump")
+    );
+}
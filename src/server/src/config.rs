@@ -15,13 +15,23 @@
 // specific language governing permissions and limitations
 // under the License.
 
+use std::thread;
+
 use common::ReadableDuration;
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct Config {
+    // Address the http server binds to, use "0.0.0.0" to accept remote
+    // connections.
+    pub bind_addr: String,
     pub port: u16,
+    // TLS is enabled when both `tls.cert_path` and `tls.key_path` are set.
+    pub tls: TlsConfig,
+    // Max time to wait for running writes and compactions when shutting down.
+    pub shutdown_timeout: ReadableDuration,
     pub test: TestConfig, // for test
     pub metric_engine: MetricEngineConfig,
 }
@@ -29,13 +39,116 @@ pub struct Config {
 impl Default for Config {
     fn default() -> Self {
         Self {
+            bind_addr: "127.0.0.1".to_string(),
             port: 5000,
+            tls: TlsConfig::default(),
+            shutdown_timeout: ReadableDuration::secs(60),
             test: TestConfig::default(),
             metric_engine: MetricEngineConfig::default(),
         }
     }
 }
 
+impl Config {
+    /// Check the config before setting up anything, all violations are
+    /// reported at once.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        let threads = &self.metric_engine.threads;
+        if self.bind_addr.is_empty() {
+            errors.push("bind_addr must not be empty, try \"127.0.0.1\"".to_string());
+        }
+        if self.tls.cert_path.is_some() != self.tls.key_path.is_some() {
+            errors.push("tls.cert_path and tls.key_path must be set together".to_string());
+        }
+        if self.tls.client_ca_path.is_some() && self.tls.cert_path.is_none() {
+            errors.push("tls.client_ca_path requires tls.cert_path and tls.key_path".to_string());
+        }
+        if threads.manifest_thread_num == 0 {
+            errors.push(
+                "metric_engine.threads.manifest_thread_num must be positive, try 2".to_string(),
+            );
+        }
+        if threads.sst_thread_num == 0 {
+            errors.push("metric_engine.threads.sst_thread_num must be positive, try 2".to_string());
+        }
+        if self.test.enable_write && self.test.write_worker_num == 0 {
+            errors.push(
+                "test.write_worker_num must be positive when test.enable_write is true".to_string(),
+            );
+        }
+        if self.test.segment_duration.is_zero() {
+            errors.push("test.segment_duration must be positive, try \"12h\"".to_string());
+        }
+        match &self.metric_engine.storage.object_store {
+            ObjectStorageConfig::Local(v) => {
+                if v.data_dir.is_empty() {
+                    errors.push(
+                        "metric_engine.storage.object_store.data_dir must not be empty".to_string(),
+                    );
+                }
+            }
+            ObjectStorageConfig::S3Like(_) => {
+                errors.push(
+                    "metric_engine.storage.object_store.type S3Like is not supported yet, use Local"
+                        .to_string(),
+                );
+            }
+        }
+        errors.extend(
+            self.metric_engine
+                .storage
+                .rate_limit
+                .violations()
+                .into_iter()
+                .map(|e| format!("metric_engine.storage.rate_limit.{e}")),
+        );
+        errors.extend(
+            self.metric_engine
+                .storage
+                .time_merge_storage
+                .violations()
+                .into_iter()
+                .map(|e| format!("metric_engine.storage.time_merge_storage.{e}")),
+        );
+
+        // Oversubscription is allowed, but it's usually a misconfiguration.
+        if let Ok(cores) = thread::available_parallelism() {
+            let total_threads = threads.manifest_thread_num
+                + threads.sst_thread_num
+                + if self.test.enable_write {
+                    self.test.write_worker_num
+                } else {
+                    0
+                };
+            if total_threads > cores.get() {
+                warn!(
+                    total_threads,
+                    cores = cores.get(),
+                    "Configured runtime threads exceed available cores"
+                );
+            }
+        }
+
+        if errors.is_empty() {
+            return Ok(());
+        }
+        Err(format!("Invalid config:\n  - {}", errors.join("\n  - ")))
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    // PEM files of the server certificate chain and its private key.
+    pub cert_path: Option<String>,
+    pub key_path: Option<String>,
+    // PEM file of CAs to verify client certificates. When set, routes under
+    // `/admin` require a client certificate signed by them, other routes accept
+    // clients without certificate.
+    pub client_ca_path: Option<String>,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(default, deny_unknown_fields)]
 pub struct TestConfig {
@@ -84,6 +197,7 @@ impl Default for ThreadConfig {
 pub struct StorageConfig {
     pub object_store: ObjectStorageConfig,
     pub time_merge_storage: metric_engine::config::StorageConfig,
+    pub rate_limit: metric_engine::config::RateLimitConfig,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
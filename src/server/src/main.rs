@@ -17,6 +17,8 @@
 
 #![feature(duration_constructors)]
 mod config;
+mod table;
+mod tls;
 use std::{
     fs,
     iter::repeat_with,
@@ -29,7 +31,9 @@ use std::{
 
 use actix_web::{
     get,
-    web::{self, Data},
+    middleware::{from_fn, Condition},
+    post,
+    web::{self, Data, Json},
     App, HttpResponse, HttpServer, Responder,
 };
 use arrow::{
@@ -42,9 +46,13 @@ use metric_engine::{
     storage::{
         CloudObjectStorage, CompactRequest, StorageRuntimes, TimeMergeStorageRef, WriteRequest,
     },
-    types::RuntimeRef,
+    store::StoreWithRateLimit,
+    types::{ObjectStoreRef, RuntimeRef},
+    Error,
 };
 use object_store::local::LocalFileSystem;
+use serde::Deserialize;
+use table::{rows_to_batch, TableDef, TableRegistry, WriteRows};
 use tracing::{error, info};
 use tracing_subscriber::EnvFilter;
 
@@ -79,8 +87,245 @@ async fn compact(data: web::Data<AppState>) -> impl Responder {
     HttpResponse::Ok().body("Task submit!")
 }
 
+#[post("/compact/pause")]
+async fn pause_compact(data: web::Data<AppState>) -> impl Responder {
+    do_pause_compact(&data.storage)
+}
+
+#[post("/compact/resume")]
+async fn resume_compact(data: web::Data<AppState>) -> impl Responder {
+    do_resume_compact(&data.storage)
+}
+
+#[post("/manifest/merge")]
+async fn merge_manifest(data: web::Data<AppState>) -> impl Responder {
+    do_merge_manifest(&data.storage).await
+}
+
+#[get("/manifest/dump")]
+async fn dump_manifest(data: web::Data<AppState>) -> impl Responder {
+    do_dump_manifest(&data.storage).await
+}
+
+#[get("/admin/ssts")]
+async fn list_ssts(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.storage.list_ssts().await)
+}
+
+#[get("/admin/manifest")]
+async fn manifest_summary(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.storage.manifest_summary().await)
+}
+
+#[get("/compact/tasks")]
+async fn compact_tasks(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.storage.compaction_tasks())
+}
+
+#[post("/admin/tables/{table}/compact/pause")]
+async fn pause_table_compact(
+    data: web::Data<AppState>,
+    table: web::Path<String>,
+) -> impl Responder {
+    set_table_compaction_paused(&data, &table, true).await
+}
+
+#[post("/admin/tables/{table}/compact/resume")]
+async fn resume_table_compact(
+    data: web::Data<AppState>,
+    table: web::Path<String>,
+) -> impl Responder {
+    set_table_compaction_paused(&data, &table, false).await
+}
+
+#[get("/admin/tables/{table}/compact/tasks")]
+async fn table_compact_tasks(
+    data: web::Data<AppState>,
+    table: web::Path<String>,
+) -> impl Responder {
+    match data.tables.get(&table).await {
+        Some(storage) => HttpResponse::Ok().json(storage.compaction_tasks()),
+        None => table_not_found(&table),
+    }
+}
+
+#[post("/admin/tables/{table}/manifest/merge")]
+async fn merge_table_manifest(
+    data: web::Data<AppState>,
+    table: web::Path<String>,
+) -> impl Responder {
+    match data.tables.get(&table).await {
+        Some(storage) => do_merge_manifest(&storage).await,
+        None => table_not_found(&table),
+    }
+}
+
+#[get("/admin/tables/{table}/manifest/dump")]
+async fn dump_table_manifest(
+    data: web::Data<AppState>,
+    table: web::Path<String>,
+) -> impl Responder {
+    match data.tables.get(&table).await {
+        Some(storage) => do_dump_manifest(&storage).await,
+        None => table_not_found(&table),
+    }
+}
+
+#[get("/admin/tables/{table}/ssts")]
+async fn list_table_ssts(data: web::Data<AppState>, table: web::Path<String>) -> impl Responder {
+    match data.tables.get(&table).await {
+        Some(storage) => HttpResponse::Ok().json(storage.list_ssts().await),
+        None => table_not_found(&table),
+    }
+}
+
+#[get("/admin/tables/{table}/manifest")]
+async fn table_manifest_summary(
+    data: web::Data<AppState>,
+    table: web::Path<String>,
+) -> impl Responder {
+    match data.tables.get(&table).await {
+        Some(storage) => HttpResponse::Ok().json(storage.manifest_summary().await),
+        None => table_not_found(&table),
+    }
+}
+
+fn do_pause_compact(storage: &TimeMergeStorageRef) -> HttpResponse {
+    storage.pause_compaction();
+    HttpResponse::Ok().body("Compaction paused!")
+}
+
+fn do_resume_compact(storage: &TimeMergeStorageRef) -> HttpResponse {
+    storage.resume_compaction();
+    HttpResponse::Ok().body("Compaction resumed!")
+}
+
+async fn do_merge_manifest(storage: &TimeMergeStorageRef) -> HttpResponse {
+    if let Err(e) = storage.merge_manifest().await {
+        return HttpResponse::InternalServerError().body(format!("Merge manifest failed, err:{e}"));
+    }
+    HttpResponse::Ok().body("Manifest merged!")
+}
+
+async fn do_dump_manifest(storage: &TimeMergeStorageRef) -> HttpResponse {
+    match storage.dump_manifest().await {
+        Ok(dump) => HttpResponse::Ok().json(dump),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Dump manifest failed, err:{e}"))
+        }
+    }
+}
+
+async fn set_table_compaction_paused(data: &AppState, table: &str, paused: bool) -> HttpResponse {
+    if data.tables.get(table).await.is_none() {
+        return table_not_found(table);
+    }
+    match data.tables.set_compaction_paused(table, paused).await {
+        Ok(()) if paused => HttpResponse::Ok().body("Compaction paused!"),
+        Ok(()) => HttpResponse::Ok().body("Compaction resumed!"),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Set compaction paused failed, err:{e}")),
+    }
+}
+
+fn table_not_found(table: &str) -> HttpResponse {
+    HttpResponse::NotFound().body(format!("Table {table} not found"))
+}
+
+#[get("/tables")]
+async fn list_tables(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.tables.table_names().await)
+}
+
+#[post("/create_table")]
+async fn create_table(data: web::Data<AppState>, def: Json<TableDef>) -> impl Responder {
+    let name = def.name.clone();
+    if let Err(e) = data.tables.create_table(def.into_inner()).await {
+        return HttpResponse::BadRequest().body(format!("Create table failed, err:{e}"));
+    }
+    HttpResponse::Ok().body(format!("Table {name} created!"))
+}
+
+#[post("/write/{table}")]
+async fn write_table(
+    data: web::Data<AppState>,
+    table: web::Path<String>,
+    req: Json<WriteRows>,
+) -> impl Responder {
+    let Some(storage) = data.tables.get(&table).await else {
+        return table_not_found(&table);
+    };
+    let batch = match rows_to_batch(&storage, &req.rows) {
+        Ok(v) => v,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid rows, err:{e}")),
+    };
+    let num_rows = batch.num_rows();
+    let write_req = WriteRequest {
+        batch,
+        time_range: (req.start..req.end).into(),
+        enable_check: true,
+    };
+    match storage.write(write_req).await {
+        Ok(()) => {}
+        Err(e @ Error::QuotaExceeded(_)) => {
+            return HttpResponse::TooManyRequests().body(format!("Write failed, err:{e}"));
+        }
+        Err(e) => {
+            return HttpResponse::InternalServerError().body(format!("Write failed, err:{e}"));
+        }
+    }
+    HttpResponse::Ok().body(format!("{num_rows} rows written!"))
+}
+
+#[derive(Deserialize)]
+struct ExportTable {
+    dest_prefix: String,
+}
+
+#[post("/export/{table}")]
+async fn export_table(
+    data: web::Data<AppState>,
+    table: web::Path<String>,
+    req: Json<ExportTable>,
+) -> impl Responder {
+    match data.tables.export_table(&table, &req.dest_prefix).await {
+        Ok(result) => HttpResponse::Ok().json(result),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Export failed, err:{e}")),
+    }
+}
+
+#[derive(Deserialize)]
+struct RestoreTable {
+    snapshot_prefix: String,
+    name: String,
+}
+
+#[post("/restore_table")]
+async fn restore_table(data: web::Data<AppState>, req: Json<RestoreTable>) -> impl Responder {
+    if let Err(e) = data
+        .tables
+        .restore_table(&req.snapshot_prefix, &req.name)
+        .await
+    {
+        return HttpResponse::InternalServerError().body(format!("Restore failed, err:{e}"));
+    }
+    HttpResponse::Ok().body(format!("Table {} restored!", req.name))
+}
+
+#[post("/compact/{table}")]
+async fn compact_table(data: web::Data<AppState>, table: web::Path<String>) -> impl Responder {
+    let Some(storage) = data.tables.get(&table).await else {
+        return table_not_found(&table);
+    };
+    if let Err(e) = storage.compact(CompactRequest::default()).await {
+        return HttpResponse::InternalServerError().body(format!("Compact failed, err:{e}"));
+    }
+    HttpResponse::Ok().body("Task submit!")
+}
+
 struct AppState {
     storage: TimeMergeStorageRef,
+    tables: Arc<TableRegistry>,
     keep_writing: Arc<AtomicBool>,
 }
 
@@ -97,8 +342,22 @@ pub fn main() {
     let config_body = fs::read_to_string(args.config).expect("read config file failed");
     let config: Config = toml::from_str(&config_body).unwrap();
     info!("Config loaded: \n{:#?}", config);
+    if let Err(e) = config.validate() {
+        error!("{e}");
+        std::process::exit(1);
+    }
 
+    let tls_config = match tls::build_server_config(&config.tls) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Load tls config failed, err:{e:#}");
+            std::process::exit(1);
+        }
+    };
+    let require_client_cert = config.tls.client_ca_path.is_some();
+    let bind_addr = config.bind_addr;
     let port = config.port;
+    let shutdown_timeout = config.shutdown_timeout.0;
     let rt = build_multi_runtime("main", 1);
     let manifest_compact_runtime = build_multi_runtime(
         "manifest-compact",
@@ -112,21 +371,35 @@ pub fn main() {
         ObjectStorageConfig::S3Like(_) => panic!("S3 not support yet"),
     };
     let time_merge_storage_config = config.metric_engine.storage.time_merge_storage;
+    let rate_limit_config = config.metric_engine.storage.rate_limit;
     let write_worker_num = config.test.write_worker_num;
     let write_interval = config.test.write_interval.0;
     let segment_duration = config.test.segment_duration.0;
     let enable_write = config.test.enable_write;
     let write_rt = build_multi_runtime("write", write_worker_num);
     let keep_writing = Arc::new(AtomicBool::new(true));
-    let _ = rt.block_on(async move {
-        let store = Arc::new(LocalFileSystem::new());
-        let storage = Arc::new(
+    rt.block_on(async move {
+        let mut store: ObjectStoreRef = Arc::new(LocalFileSystem::new());
+        if rate_limit_config.is_enabled() {
+            store = Arc::new(StoreWithRateLimit::new(store, &rate_limit_config));
+        }
+        let storage: TimeMergeStorageRef = Arc::new(
             CloudObjectStorage::try_new(
-                object_store_config.data_dir,
+                object_store_config.data_dir.clone(),
                 segment_duration,
-                store,
+                store.clone(),
                 build_schema(),
                 3,
+                time_merge_storage_config.clone(),
+                runtimes.clone(),
+            )
+            .await
+            .unwrap(),
+        );
+        let tables = Arc::new(
+            TableRegistry::open(
+                &object_store_config.data_dir,
+                store,
                 time_merge_storage_config,
                 runtimes,
             )
@@ -145,22 +418,74 @@ pub fn main() {
         }
 
         let app_state = Data::new(AppState {
-            storage,
-            keep_writing,
+            storage: storage.clone(),
+            tables: tables.clone(),
+            keep_writing: keep_writing.clone(),
         });
-        info!(port, "Start HoraeDB http server...");
-        HttpServer::new(move || {
+        info!(
+            bind_addr,
+            port,
+            tls = tls_config.is_some(),
+            "Start HoraeDB http server..."
+        );
+        let server = HttpServer::new(move || {
+            // Routes are matched in order, so `/compact/pause` and `/compact/resume`
+            // must be registered before `/compact/{table}`.
             App::new()
                 .app_data(app_state.clone())
+                .wrap(Condition::new(
+                    require_client_cert,
+                    from_fn(tls::require_client_cert),
+                ))
                 .service(hello)
                 .service(compact)
+                .service(pause_compact)
+                .service(resume_compact)
+                .service(compact_tasks)
+                .service(merge_manifest)
+                .service(dump_manifest)
+                .service(list_ssts)
+                .service(manifest_summary)
                 .service(toggle)
+                .service(list_tables)
+                .service(create_table)
+                .service(write_table)
+                .service(compact_table)
+                .service(export_table)
+                .service(restore_table)
+                .service(pause_table_compact)
+                .service(resume_table_compact)
+                .service(table_compact_tasks)
+                .service(merge_table_manifest)
+                .service(dump_table_manifest)
+                .service(list_table_ssts)
+                .service(table_manifest_summary)
         })
-        .workers(4)
-        .bind(("127.0.0.1", port))
-        .expect("Server bind failed")
-        .run()
-        .await
+        .on_connect(tls::record_client_cert)
+        .workers(4);
+        let server = match tls_config {
+            Some(tls_config) => server.bind_rustls_0_23((bind_addr.as_str(), port), tls_config),
+            None => server.bind((bind_addr.as_str(), port)),
+        };
+        server
+            .expect("Server bind failed")
+            .run()
+            .await
+            .expect("Server run failed");
+
+        // The server returns after SIGINT/SIGTERM, stop writing before exit so that
+        // no write is interrupted halfway.
+        info!("Shutting down HoraeDB...");
+        keep_writing.store(false, Ordering::Relaxed);
+        let close_all = async {
+            tables.close().await;
+            storage.close().await
+        };
+        match tokio::time::timeout(shutdown_timeout, close_all).await {
+            Ok(Ok(())) => info!("HoraeDB shutdown"),
+            Ok(Err(e)) => error!("Close storage failed, err:{e}"),
+            Err(_) => error!(?shutdown_timeout, "Close storage timeout"),
+        }
     });
 }
 
@@ -231,3 +556,93 @@ fn bench_write(
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::StatusCode,
+        test::{call_service, init_service, TestRequest},
+    };
+    use metric_engine::config::StorageConfig;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_merge_manifest_routes() {
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let root_dir = root_dir.path().to_string_lossy().to_string();
+        let rt = build_multi_runtime("test", 1);
+        let runtimes = StorageRuntimes::new(rt.clone(), rt);
+        // Actix runs handlers on current_thread runtime.
+        actix_web::rt::System::new().block_on(async {
+            let store: ObjectStoreRef = Arc::new(LocalFileSystem::new());
+            let storage: TimeMergeStorageRef = Arc::new(
+                CloudObjectStorage::try_new(
+                    format!("{root_dir}/bench"),
+                    Duration::from_hours(2),
+                    store.clone(),
+                    build_schema(),
+                    3,
+                    StorageConfig::default(),
+                    runtimes.clone(),
+                )
+                .await
+                .unwrap(),
+            );
+            let tables = Arc::new(
+                TableRegistry::open(&root_dir, store, StorageConfig::default(), runtimes.clone())
+                    .await
+                    .unwrap(),
+            );
+            let def: TableDef = serde_json::from_value(json!({
+                "name": "cpu",
+                "columns": [
+                    {"name": "host", "data_type": "Utf8"},
+                    {"name": "value", "data_type": "Float64"},
+                ],
+                "num_primary_keys": 1,
+            }))
+            .unwrap();
+            tables.create_table(def).await.unwrap();
+            let app = init_service(
+                App::new()
+                    .app_data(Data::new(AppState {
+                        storage: storage.clone(),
+                        tables: tables.clone(),
+                        keep_writing: Arc::new(AtomicBool::new(false)),
+                    }))
+                    .service(merge_manifest)
+                    .service(write_table)
+                    .service(merge_table_manifest),
+            )
+            .await;
+
+            let req = TestRequest::post()
+                .uri("/write/cpu")
+                .set_json(json!({"start": 0, "end": 10, "rows": [{"host": "a", "value": 1.0}]}))
+                .to_request();
+            assert!(call_service(&app, req).await.status().is_success());
+            let cpu = tables.get("cpu").await.unwrap();
+            assert_eq!(cpu.dump_manifest().await.unwrap().deltas.len(), 1);
+
+            for uri in ["/manifest/merge", "/admin/tables/cpu/manifest/merge"] {
+                let req = TestRequest::post().uri(uri).to_request();
+                let resp = call_service(&app, req).await;
+                assert_eq!(resp.status(), StatusCode::OK, "{uri}");
+            }
+            let dump = cpu.dump_manifest().await.unwrap();
+            assert_eq!(dump.snapshot.len(), 1);
+            assert!(dump.deltas.is_empty());
+
+            let req = TestRequest::post()
+                .uri("/admin/tables/mem/manifest/merge")
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+            tables.close().await;
+            storage.close().await.unwrap();
+        });
+    }
+}
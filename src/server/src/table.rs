@@ -0,0 +1,598 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! Named tables, each one is backed by an independent storage.
+
+use std::{collections::HashMap, path::Component, str::FromStr, sync::Arc};
+
+use anyhow::Context;
+use arrow::{
+    array::RecordBatch,
+    datatypes::{DataType, Field, Schema},
+    json::ReaderBuilder,
+};
+use common::ReadableDuration;
+use futures::TryStreamExt;
+use metric_engine::{
+    config::{QuotaConfig, StorageConfig},
+    storage::{
+        restore_snapshot, CloudObjectStorage, ExportSnapshotResult, StorageRuntimes,
+        TimeMergeStorageRef,
+    },
+    types::{ObjectStoreRef, StorageSchema},
+    AnyhowError, Error, Result,
+};
+use object_store::{path::Path, PutPayload};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::{error, info, warn};
+
+const TABLES_DIR: &str = "tables";
+const TABLE_DEF_FILENAME: &str = "table.json";
+const SNAPSHOTS_DIR: &str = "snapshots";
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ColumnDef {
+    pub name: String,
+    // Arrow data type, such as "Int64" or "Utf8".
+    pub data_type: String,
+}
+
+/// Definition of a table, persisted as `{root_dir}/tables/{name}/table.json`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TableDef {
+    pub name: String,
+    // Leading `num_primary_keys` columns are primary keys.
+    pub columns: Vec<ColumnDef>,
+    pub num_primary_keys: usize,
+    #[serde(default = "default_segment_duration")]
+    pub segment_duration: ReadableDuration,
+    // Overrides the quota of storage config.
+    #[serde(default)]
+    pub quota: Option<QuotaConfig>,
+    // Set by `TableRegistry::set_compaction_paused`, so that the table is still
+    // paused after restart.
+    #[serde(default)]
+    pub compaction_paused: bool,
+}
+
+#[inline]
+fn default_segment_duration() -> ReadableDuration {
+    ReadableDuration::hours(12)
+}
+
+impl TableDef {
+    fn validate(&self) -> Result<()> {
+        let valid_name = !self.name.is_empty()
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            return Err(AnyhowError::msg(format!(
+                "table name must be non-empty and only contain [a-zA-Z0-9_], name:{}",
+                self.name
+            ))
+            .into());
+        }
+        if self.segment_duration.is_zero() {
+            return Err(AnyhowError::msg("segment_duration must be positive").into());
+        }
+        if let Some(quota) = &self.quota {
+            let config = StorageConfig {
+                quota: quota.clone(),
+                ..Default::default()
+            };
+            config.validate()?;
+        }
+
+        Ok(())
+    }
+
+    fn build_schema(&self) -> Result<Schema> {
+        let fields = self
+            .columns
+            .iter()
+            .map(|c| {
+                let data_type = DataType::from_str(&c.data_type)
+                    .with_context(|| format!("invalid data type of column {}", c.name))?;
+                Ok(Field::new(&c.name, data_type, true))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Schema::new(fields))
+    }
+}
+
+/// Rows to write into a table, all rows should be in `[start, end)`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WriteRows {
+    pub start: i64,
+    pub end: i64,
+    pub rows: Vec<serde_json::Map<String, serde_json::Value>>,
+}
+
+pub struct TableRegistry {
+    root_dir: String,
+    // Exported snapshots are put under this dir.
+    snapshots_dir: String,
+    store: ObjectStoreRef,
+    storage_config: StorageConfig,
+    runtimes: StorageRuntimes,
+    tables: RwLock<HashMap<String, TimeMergeStorageRef>>,
+}
+
+impl TableRegistry {
+    /// Open the registry, tables created before are opened as well.
+    pub async fn open(
+        root_dir: &str,
+        store: ObjectStoreRef,
+        storage_config: StorageConfig,
+        runtimes: StorageRuntimes,
+    ) -> Result<Self> {
+        let registry = Self {
+            root_dir: format!("{root_dir}/{TABLES_DIR}"),
+            snapshots_dir: format!("{root_dir}/{SNAPSHOTS_DIR}"),
+            store,
+            storage_config,
+            runtimes,
+            tables: RwLock::new(HashMap::new()),
+        };
+
+        let table_dirs = registry
+            .store
+            .list_with_delimiter(Some(&Path::from(registry.root_dir.as_str())))
+            .await
+            .context("list table dirs")?
+            .common_prefixes;
+        let mut tables = registry.tables.write().await;
+        for dir in table_dirs {
+            // Dirs without def are left by failed create/restore, they are removed
+            // when a table with the same name is created again.
+            let def = match registry.read_def(&dir.child(TABLE_DEF_FILENAME)).await {
+                Ok(v) => v,
+                Err(e) if is_not_found(&e) => {
+                    warn!(%dir, "Skip table dir without def");
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let storage = registry.open_storage(&def).await?;
+            info!(table = def.name, "Table opened");
+            tables.insert(def.name, storage);
+        }
+        drop(tables);
+
+        Ok(registry)
+    }
+
+    pub async fn create_table(&self, def: TableDef) -> Result<()> {
+        def.validate()?;
+        let mut tables = self.tables.write().await;
+        if tables.contains_key(&def.name) {
+            return Err(AnyhowError::msg(format!("table {} already exists", def.name)).into());
+        }
+
+        let root_dir = format!("{}/{}", self.root_dir, def.name);
+        self.remove_dir(&root_dir).await?;
+        let res = async {
+            let storage = self.open_storage(&def).await?;
+            if let Err(e) = self.write_def(&def, &root_dir).await {
+                self.close_storage(&def.name, storage).await;
+                return Err(e);
+            }
+            Ok(storage)
+        }
+        .await;
+        let storage = self.cleanup_on_error(&root_dir, res).await?;
+        info!(table = def.name, "Table created");
+        tables.insert(def.name, storage);
+
+        Ok(())
+    }
+
+    /// Export the table's ssts and definition to `dest_prefix`, which is
+    /// relative to `{data_dir}/snapshots`, see
+    /// `TimeMergeStorage::export_snapshot`.
+    pub async fn export_table(
+        &self,
+        name: &str,
+        dest_prefix: &str,
+    ) -> Result<ExportSnapshotResult> {
+        let storage = self
+            .get(name)
+            .await
+            .with_context(|| format!("table {name} not found"))?;
+        let def = self
+            .read_def(&Path::from(format!(
+                "{}/{name}/{TABLE_DEF_FILENAME}",
+                self.root_dir
+            )))
+            .await?;
+        let dest_prefix = self.resolve_snapshot_prefix(dest_prefix)?;
+        let result = storage.export_snapshot(&dest_prefix).await?;
+        self.write_def(&def, &dest_prefix).await?;
+
+        Ok(result)
+    }
+
+    /// Create table `name` from a snapshot exported by `export_table`.
+    pub async fn restore_table(&self, snapshot_prefix: &str, name: &str) -> Result<()> {
+        let snapshot_prefix = self.resolve_snapshot_prefix(snapshot_prefix)?;
+        let mut def = self
+            .read_def(&Path::from(format!(
+                "{snapshot_prefix}/{TABLE_DEF_FILENAME}"
+            )))
+            .await?;
+        def.name = name.to_string();
+        def.validate()?;
+        let mut tables = self.tables.write().await;
+        if tables.contains_key(&def.name) {
+            return Err(AnyhowError::msg(format!("table {} already exists", def.name)).into());
+        }
+
+        let root_dir = format!("{}/{}", self.root_dir, def.name);
+        self.remove_dir(&root_dir).await?;
+        let res = async {
+            restore_snapshot(&self.store, &snapshot_prefix, &root_dir).await?;
+            let storage = self.open_storage(&def).await?;
+            if let Err(e) = self.write_def(&def, &root_dir).await {
+                self.close_storage(&def.name, storage).await;
+                return Err(e);
+            }
+            Ok(storage)
+        }
+        .await;
+        let storage = self.cleanup_on_error(&root_dir, res).await?;
+        info!(table = def.name, snapshot_prefix, "Table restored");
+        tables.insert(def.name, storage);
+
+        Ok(())
+    }
+
+    /// Pause or resume compaction of table `name`, the state is persisted in
+    /// its def.
+    pub async fn set_compaction_paused(&self, name: &str, paused: bool) -> Result<()> {
+        // Hold the write lock so concurrent updates of the def won't be lost.
+        let tables = self.tables.write().await;
+        let storage = tables
+            .get(name)
+            .with_context(|| format!("table {name} not found"))?;
+        let root_dir = format!("{}/{name}", self.root_dir);
+        let mut def = self
+            .read_def(&Path::from(format!("{root_dir}/{TABLE_DEF_FILENAME}")))
+            .await?;
+        def.compaction_paused = paused;
+        self.write_def(&def, &root_dir).await?;
+        if paused {
+            storage.pause_compaction();
+        } else {
+            storage.resume_compaction();
+        }
+
+        Ok(())
+    }
+
+    pub async fn get(&self, name: &str) -> Option<TimeMergeStorageRef> {
+        self.tables.read().await.get(name).cloned()
+    }
+
+    pub async fn table_names(&self) -> Vec<String> {
+        let mut names = self.tables.read().await.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Close all tables, errors are logged and won't stop closing others.
+    pub async fn close(&self) {
+        let tables = self.tables.read().await;
+        for (name, storage) in tables.iter() {
+            self.close_storage(name, storage.clone()).await;
+        }
+    }
+
+    /// Resolve `prefix` under the snapshots dir, only relative paths without
+    /// `..` are allowed.
+    fn resolve_snapshot_prefix(&self, prefix: &str) -> Result<String> {
+        let mut components = std::path::Path::new(prefix).components().peekable();
+        let valid =
+            components.peek().is_some() && components.all(|c| matches!(c, Component::Normal(_)));
+        if !valid {
+            return Err(AnyhowError::msg(format!(
+                "snapshot prefix must be a relative path without `..`, prefix:{prefix}"
+            ))
+            .into());
+        }
+
+        Ok(format!("{}/{prefix}", self.snapshots_dir))
+    }
+
+    /// Remove all objects under `dir`, leftovers of a failed create/restore.
+    async fn remove_dir(&self, dir: &str) -> Result<()> {
+        let paths = self
+            .store
+            .list(Some(&Path::from(dir)))
+            .map_ok(|meta| meta.location)
+            .try_collect::<Vec<_>>()
+            .await
+            .with_context(|| format!("list table dir, path:{dir}"))?;
+        for path in paths {
+            self.store
+                .delete(&path)
+                .await
+                .with_context(|| format!("delete table file, path:{path}"))?;
+        }
+
+        Ok(())
+    }
+
+    async fn cleanup_on_error<T>(&self, dir: &str, res: Result<T>) -> Result<T> {
+        if res.is_err() {
+            if let Err(e) = self.remove_dir(dir).await {
+                error!(dir, "Remove table dir failed, err:{e}");
+            }
+        }
+        res
+    }
+
+    async fn close_storage(&self, name: &str, storage: TimeMergeStorageRef) {
+        if let Err(e) = storage.close().await {
+            error!(table = name, "Close table failed, err:{e}");
+        }
+    }
+
+    async fn read_def(&self, path: &Path) -> Result<TableDef> {
+        let body = self
+            .store
+            .get(path)
+            .await
+            .with_context(|| format!("read table def, path:{path}"))?
+            .bytes()
+            .await
+            .with_context(|| format!("read table def, path:{path}"))?;
+        let def = serde_json::from_slice(&body)
+            .with_context(|| format!("decode table def, path:{path}"))?;
+
+        Ok(def)
+    }
+
+    async fn write_def(&self, def: &TableDef, dir: &str) -> Result<()> {
+        let path = Path::from(format!("{dir}/{TABLE_DEF_FILENAME}"));
+        let body = serde_json::to_vec(def).context("encode table def")?;
+        self.store
+            .put(&path, PutPayload::from(body))
+            .await
+            .with_context(|| format!("write table def, path:{path}"))?;
+
+        Ok(())
+    }
+
+    async fn open_storage(&self, def: &TableDef) -> Result<TimeMergeStorageRef> {
+        let schema = def.build_schema()?;
+        let mut storage_config = self.storage_config.clone();
+        if let Some(quota) = &def.quota {
+            storage_config.quota = quota.clone();
+        }
+        storage_config.scheduler.paused |= def.compaction_paused;
+        let storage = CloudObjectStorage::try_new(
+            format!("{}/{}", self.root_dir, def.name),
+            def.segment_duration.0,
+            self.store.clone(),
+            Arc::new(schema),
+            def.num_primary_keys,
+            storage_config,
+            self.runtimes.clone(),
+        )
+        .await
+        .with_context(|| format!("open table {}", def.name))?;
+
+        Ok(Arc::new(storage))
+    }
+}
+
+fn is_not_found(e: &Error) -> bool {
+    match e {
+        Error::Internal(e) => matches!(
+            e.downcast_ref::<object_store::Error>(),
+            Some(object_store::Error::NotFound { .. })
+        ),
+        _ => false,
+    }
+}
+
+/// Convert json rows into a record batch of `storage`'s schema.
+pub fn rows_to_batch(
+    storage: &TimeMergeStorageRef,
+    rows: &[serde_json::Map<String, serde_json::Value>],
+) -> Result<RecordBatch> {
+    // Builtin columns are filled by storage when writing.
+    let fields = storage
+        .schema()
+        .fields()
+        .iter()
+        .filter(|f| !StorageSchema::is_builtin_field(f))
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut decoder = ReaderBuilder::new(Arc::new(Schema::new(fields)))
+        .with_batch_size(rows.len().max(1))
+        .build_decoder()
+        .context("build json decoder")?;
+    decoder.serialize(rows).context("decode rows")?;
+    let batch = decoder
+        .flush()
+        .context("decode rows")?
+        .context("no rows to write")?;
+
+    Ok(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use metric_engine::storage::WriteRequest;
+    use object_store::local::LocalFileSystem;
+    use serde_json::json;
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    #[test]
+    fn test_table_registry() {
+        let root_dir = temp_dir::TempDir::new().unwrap();
+        let root_dir = root_dir.path().to_string_lossy().to_string();
+        let rt = Arc::new(Runtime::new().unwrap());
+        let runtimes = StorageRuntimes::new(rt.clone(), rt.clone());
+        rt.block_on(async move {
+            let store: ObjectStoreRef = Arc::new(LocalFileSystem::new());
+            let open = || {
+                TableRegistry::open(
+                    &root_dir,
+                    store.clone(),
+                    StorageConfig::default(),
+                    runtimes.clone(),
+                )
+            };
+            let registry = open().await.unwrap();
+            assert!(registry.table_names().await.is_empty());
+
+            let def: TableDef = serde_json::from_value(json!({
+                "name": "cpu",
+                "columns": [
+                    {"name": "host", "data_type": "Utf8"},
+                    {"name": "value", "data_type": "Float64"},
+                ],
+                "num_primary_keys": 1,
+            }))
+            .unwrap();
+            registry.create_table(def.clone()).await.unwrap();
+            assert!(registry.create_table(def.clone()).await.is_err());
+            let invalid = TableDef {
+                name: "a/b".to_string(),
+                ..def
+            };
+            assert!(registry.create_table(invalid).await.is_err());
+
+            let storage = registry.get("cpu").await.unwrap();
+            let rows = json!([{"host": "a", "value": 1.0}, {"host": "b", "value": 2.5}]);
+            let batch =
+                rows_to_batch(&storage, &serde_json::from_value::<Vec<_>>(rows).unwrap()).unwrap();
+            assert_eq!(batch.num_rows(), 2);
+            storage
+                .write(WriteRequest {
+                    batch,
+                    time_range: (0..10).into(),
+                    enable_check: true,
+                })
+                .await
+                .unwrap();
+            registry.close().await;
+
+            let registry = open().await.unwrap();
+            assert_eq!(registry.table_names().await, vec!["cpu".to_string()]);
+            let storage = registry.get("cpu").await.unwrap();
+            assert_eq!(storage.list_ssts().await.len(), 1);
+            assert!(registry.get("mem").await.is_none());
+
+            for prefix in ["", "/tmp/backup", "../backup", "a/../../backup", "."] {
+                assert!(registry.export_table("cpu", prefix).await.is_err());
+                assert!(registry.restore_table(prefix, "cpu3").await.is_err());
+            }
+            let backup = "backup";
+            let result = registry.export_table("cpu", backup).await.unwrap();
+            assert_eq!(result.ssts.len(), 1);
+            registry.restore_table(backup, "cpu2").await.unwrap();
+            assert!(registry.restore_table(backup, "cpu2").await.is_err());
+            let restored = registry.get("cpu2").await.unwrap();
+            assert_eq!(restored.list_ssts().await, storage.list_ssts().await);
+            assert_eq!(
+                registry.table_names().await,
+                vec!["cpu".to_string(), "cpu2".to_string()]
+            );
+
+            // Failed restore leaves nothing behind.
+            assert!(registry.restore_table("missing", "cpu3").await.is_err());
+            let partial = Path::from(format!("{root_dir}/{TABLES_DIR}/cpu3"));
+            assert!(store.list(Some(&partial)).next().await.is_none());
+            registry.close().await;
+
+            // Dirs without def, such as left by a crash, are skipped.
+            store
+                .put(
+                    &partial.child("manifest").child("snapshot"),
+                    PutPayload::new(),
+                )
+                .await
+                .unwrap();
+            let registry = open().await.unwrap();
+            assert_eq!(
+                registry.table_names().await,
+                vec!["cpu".to_string(), "cpu2".to_string()]
+            );
+            registry.restore_table(backup, "cpu3").await.unwrap();
+            let restored = registry.get("cpu3").await.unwrap();
+            assert_eq!(restored.list_ssts().await.len(), 1);
+
+            // Pause is kept after restart.
+            registry.set_compaction_paused("cpu", true).await.unwrap();
+            assert!(registry.get("cpu").await.unwrap().is_compaction_paused());
+            assert!(registry.set_compaction_paused("disk", true).await.is_err());
+            registry.close().await;
+            let registry = open().await.unwrap();
+            assert!(registry.get("cpu").await.unwrap().is_compaction_paused());
+            assert!(!registry.get("cpu2").await.unwrap().is_compaction_paused());
+            registry.set_compaction_paused("cpu", false).await.unwrap();
+            registry.close().await;
+            let registry = open().await.unwrap();
+            assert!(!registry.get("cpu").await.unwrap().is_compaction_paused());
+
+            // Quota in table def overrides the storage config.
+            let mut def: TableDef = serde_json::from_value(json!({
+                "name": "mem",
+                "columns": [
+                    {"name": "host", "data_type": "Utf8"},
+                    {"name": "value", "data_type": "Float64"},
+                ],
+                "num_primary_keys": 1,
+                "quota": {"write_rows_per_sec": 0},
+            }))
+            .unwrap();
+            assert!(registry.create_table(def.clone()).await.is_err());
+            def.quota = Some(QuotaConfig {
+                write_rows_per_sec: Some(1),
+                ..Default::default()
+            });
+            registry.create_table(def).await.unwrap();
+            let storage = registry.get("mem").await.unwrap();
+            let rows = json!([{"host": "a", "value": 1.0}, {"host": "b", "value": 2.5}]);
+            let batch =
+                rows_to_batch(&storage, &serde_json::from_value::<Vec<_>>(rows).unwrap()).unwrap();
+            let write = || {
+                storage.write(WriteRequest {
+                    batch: batch.clone(),
+                    time_range: (0..10).into(),
+                    enable_check: true,
+                })
+            };
+            // A full bucket admits one oversized write.
+            write().await.unwrap();
+            assert!(matches!(write().await, Err(Error::QuotaExceeded(_))));
+        });
+    }
+}
@@ -0,0 +1,269 @@
+// Licensed to the Apache Software Foundation (ASF) under one
+// or more contributor license agreements.  See the NOTICE file
+// distributed with this work for additional information
+// regarding copyright ownership.  The ASF licenses this file
+// to you under the Apache License, Version 2.0 (the
+// "License"); you may not use this file except in compliance
+// with the License.  You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing,
+// software distributed under the License is distributed on an
+// "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF ANY
+// KIND, either express or implied.  See the License for the
+// specific language governing permissions and limitations
+// under the License.
+
+//! TLS termination of the http server.
+
+use std::{any::Any, fs::File, io::BufReader, sync::Arc};
+
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::{
+    body::MessageBody,
+    dev::{Extensions, ServiceRequest, ServiceResponse},
+    middleware::Next,
+    rt::net::TcpStream,
+    HttpResponse,
+};
+use anyhow::{ensure, Context, Result};
+use rustls::{
+    crypto::ring, pki_types::CertificateDer, server::WebPkiClientVerifier, RootCertStore,
+    ServerConfig,
+};
+
+use crate::config::TlsConfig;
+
+// Routes under it require a client certificate when client CA is configured.
+const ADMIN_PATH_PREFIX: &str = "/admin/";
+
+/// Set in connection data when the client presents a certificate, which is
+/// verified during handshake.
+#[derive(Clone, Copy)]
+struct ClientCertVerified;
+
+/// Build rustls config, `None` means TLS is disabled.
+pub fn build_server_config(config: &TlsConfig) -> Result<Option<ServerConfig>> {
+    let (Some(cert_path), Some(key_path)) = (&config.cert_path, &config.key_path) else {
+        return Ok(None);
+    };
+    let certs = load_certs(cert_path)?;
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)
+        .with_context(|| format!("read private key, path:{key_path}"))?
+        .with_context(|| format!("no private key found, path:{key_path}"))?;
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .context("build tls config")?;
+    let builder = match &config.client_ca_path {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_path)? {
+                roots
+                    .add(cert)
+                    .with_context(|| format!("add client ca, path:{ca_path}"))?;
+            }
+            // Clients without certificate are accepted here, so that only admin
+            // routes require it, see `require_client_cert`.
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .allow_unauthenticated()
+                .build()
+                .context("build client cert verifier")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(certs, key)
+        .context("invalid tls cert or key")?;
+
+    Ok(Some(config))
+}
+
+/// Used by `HttpServer::on_connect` to record whether the client presents a
+/// certificate.
+pub fn record_client_cert(conn: &dyn Any, ext: &mut Extensions) {
+    if let Some(stream) = conn.downcast_ref::<TlsStream<TcpStream>>() {
+        let (_, session) = stream.get_ref();
+        if session.peer_certificates().is_some_and(|v| !v.is_empty()) {
+            ext.insert(ClientCertVerified);
+        }
+    }
+}
+
+/// Reject requests to admin routes from clients without certificate.
+pub async fn require_client_cert(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> actix_web::Result<ServiceResponse<impl MessageBody>> {
+    // Check the path used for routing, which has percent-encoded chars decoded.
+    let is_admin = req.match_info().as_str().starts_with(ADMIN_PATH_PREFIX);
+    if is_admin && req.conn_data::<ClientCertVerified>().is_none() {
+        let resp = HttpResponse::Forbidden().body("Client certificate is required");
+        return Ok(req.into_response(resp).map_into_right_body());
+    }
+
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)
+        .collect::<std::io::Result<Vec<_>>>()
+        .with_context(|| format!("read certs, path:{path}"))?;
+    ensure!(!certs.is_empty(), "no cert found, path:{path}");
+
+    Ok(certs)
+}
+
+fn open(path: &str) -> Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("open file, path:{path}"))?;
+    Ok(BufReader::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{middleware::from_fn, web, App, HttpServer};
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::{
+        pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer, ServerName},
+        ClientConfig,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    struct Ca {
+        cert: Certificate,
+        key: KeyPair,
+    }
+
+    impl Ca {
+        fn new() -> Self {
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let key = KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            Self { cert, key }
+        }
+
+        fn issue(&self, name: &str, usage: ExtendedKeyUsagePurpose) -> (Certificate, KeyPair) {
+            let mut params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            params.extended_key_usages = vec![usage];
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+            (cert, key)
+        }
+    }
+
+    /// Send a GET request and returns the status code, `None` means the
+    /// connection is rejected.
+    async fn get(
+        addr: std::net::SocketAddr,
+        ca: &Ca,
+        client_cert: Option<&(Certificate, KeyPair)>,
+        path: &str,
+    ) -> Option<u16> {
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.cert.der().clone()).unwrap();
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = match client_cert {
+            Some((cert, key)) => {
+                let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+                builder
+                    .with_client_auth_cert(vec![cert.der().clone()], key)
+                    .unwrap()
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(server_name, stream)
+            .await
+            .ok()?;
+        let req = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        stream.write_all(req.as_bytes()).await.ok()?;
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).await.ok()?;
+        // Status line is like `HTTP/1.1 200 OK`.
+        resp.split_whitespace().nth(1)?.parse().ok()
+    }
+
+    #[test]
+    fn test_build_server_config() {
+        assert!(build_server_config(&TlsConfig::default())
+            .unwrap()
+            .is_none());
+        let config = TlsConfig {
+            cert_path: Some("/not/exist/cert.pem".to_string()),
+            key_path: Some("/not/exist/key.pem".to_string()),
+            client_ca_path: None,
+        };
+        let err = build_server_config(&config).unwrap_err();
+        assert!(err.to_string().contains("/not/exist/cert.pem"), "{err}");
+    }
+
+    #[test]
+    fn test_client_cert_for_admin_routes() {
+        let dir = temp_dir::TempDir::new().unwrap();
+        let write = |name: &str, pem: String| {
+            let path = dir.child(name);
+            std::fs::write(&path, pem).unwrap();
+            Some(path.to_string_lossy().to_string())
+        };
+        let ca = Ca::new();
+        let (server_cert, server_key) = ca.issue("localhost", ExtendedKeyUsagePurpose::ServerAuth);
+        let client = ca.issue("client", ExtendedKeyUsagePurpose::ClientAuth);
+        let other_client = Ca::new().issue("client", ExtendedKeyUsagePurpose::ClientAuth);
+        let config = TlsConfig {
+            cert_path: write("server.pem", server_cert.pem()),
+            key_path: write("server.key", server_key.serialize_pem()),
+            client_ca_path: write("ca.pem", ca.cert.pem()),
+        };
+        let server_config = build_server_config(&config).unwrap().unwrap();
+
+        actix_web::rt::System::new().block_on(async move {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            let server = HttpServer::new(|| {
+                App::new()
+                    .wrap(from_fn(require_client_cert))
+                    .route("/ping", web::get().to(|| async { "pong" }))
+                    .route("/admin/ping", web::get().to(|| async { "pong" }))
+            })
+            .on_connect(record_client_cert)
+            .workers(1)
+            .listen_rustls_0_23(listener, server_config)
+            .unwrap()
+            .run();
+            let handle = server.handle();
+            actix_web::rt::spawn(server);
+
+            assert_eq!(get(addr, &ca, None, "/ping").await, Some(200));
+            assert_eq!(get(addr, &ca, None, "/admin/ping").await, Some(403));
+            assert_eq!(get(addr, &ca, None, "/%61dmin/ping").await, Some(403));
+            assert_eq!(
+                get(addr, &ca, Some(&client), "/admin/ping").await,
+                Some(200)
+            );
+            assert_eq!(
+                get(addr, &ca, Some(&client), "/%61dmin/ping").await,
+                Some(200)
+            );
+            // Certificate not signed by the client CA is rejected in handshake.
+            assert_eq!(get(addr, &ca, Some(&other_client), "/ping").await, None);
+
+            handle.stop(true).await;
+        });
+    }
+}